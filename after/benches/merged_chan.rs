@@ -0,0 +1,105 @@
+//! Compare the heap based MergedChannels against the sorted vec strategy it replaced
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use crossbeam::channel::Receiver;
+use scale::merged_chan::MergedChannels;
+
+const LINES_PER_CHANNEL: usize = 1000;
+
+/// The previous merge strategy, head items kept in a sorted vec and exhausted channels swap
+/// removed with the displaced id found by a linear search
+struct SortedVecMerge<T> {
+    chans: Vec<Receiver<T>>,
+    head_items: Vec<(T, usize)>,
+    last_picked: Option<usize>,
+}
+
+impl<T: Ord> SortedVecMerge<T> {
+    fn new(chans: Vec<Receiver<T>>) -> Self {
+        Self {
+            chans,
+            head_items: vec![],
+            last_picked: None,
+        }
+    }
+
+    fn receive_from(&mut self, id: usize) {
+        match self.chans[id].recv() {
+            Ok(item) => {
+                let item = (item, id);
+                let ind = self
+                    .head_items
+                    .binary_search_by(|probe| probe.cmp(&item).reverse())
+                    .unwrap_err();
+                self.head_items.insert(ind, item);
+            }
+            Err(_) => {
+                let _ = self.chans.swap_remove(id);
+                let old_id = self.chans.len();
+                if let Some(item) = self.head_items.iter_mut().find(|item| item.1 == old_id) {
+                    item.1 = id;
+                }
+            }
+        }
+    }
+}
+
+impl<T: Ord> Iterator for SortedVecMerge<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.chans.is_empty() {
+            return None;
+        }
+
+        match self.last_picked {
+            Some(id) => self.receive_from(id),
+            None => (0..self.chans.len()).for_each(|id| self.receive_from(id)),
+        }
+
+        self.head_items.pop().map(|(item, id)| {
+            self.last_picked = Some(id);
+            item
+        })
+    }
+}
+
+/// Build a set of closed channels each holding an interleaved sorted sequence
+fn filled_channels(count: usize) -> Vec<Receiver<usize>> {
+    (0..count)
+        .map(|id| {
+            let (s, r) = crossbeam::channel::unbounded();
+            for line in 0..LINES_PER_CHANNEL {
+                s.send(line * count + id).unwrap();
+            }
+            r
+        })
+        .collect()
+}
+
+fn merge_strategies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge");
+
+    for &count in &[4, 64, 512] {
+        group.bench_with_input(BenchmarkId::new("sorted_vec", count), &count, |b, &count| {
+            b.iter_batched(
+                || filled_channels(count),
+                |chans| SortedVecMerge::new(chans).count(),
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("binary_heap", count), &count, |b, &count| {
+            b.iter_batched(
+                || filled_channels(count),
+                |chans| MergedChannels::new(chans).count(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, merge_strategies);
+criterion_main!(benches);
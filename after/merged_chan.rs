@@ -1,55 +1,72 @@
-//! Struct for merging multiple sorted channels into a single iterator
+//! Structs for merging multiple sorted channels, iterators, or streams into a single sorted
+//! sequence
 
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{Receiver, Select, TryRecvError};
+use futures::stream::Stream;
 use std::cmp::Ordering;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::io::{self, BufRead};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 /// Representation of a merged set of channels as an iterator
 ///
-/// Depends upon the assumption that all data in chans is already sorted.
+/// Depends upon the assumption that all data in chans is already sorted according to cmp.
 ///
 /// Waits on chans at start of each next call to ensure that we have one head_item per channel.
 ///
-/// Upon reading each head_item they are inserted into head_items using a binary_search and insert.
+/// Upon reading each head_item they are pushed onto the head_items min heap along with the id of
+/// the channel they came from.
 ///
-/// Once we have as many head_items as chans we pop the head and save the index that the item came
-/// from. On the next iteration we wait on that channel before repeating the insert and pop.
+/// Once we have as many head_items as live chans we pop the smallest and save the id that the
+/// item came from. On the next iteration we wait on that channel before repeating the push and
+/// pop.
 ///
-/// Once we exhaust a channel we swap the exhausted channel with the last one, pop it, and find the
-/// highest ID in head_items and replace it with the new ID which was assigned to the exhausted
-/// channel.
+/// Channel ids are the position of the channel in the vec passed to the constructor and never
+/// change. An exhausted channel is simply never waited on again rather than removed, so both
+/// inserting a head_item and retiring a channel are at worst logarithmic in the number of
+/// channels.
 ///
-/// Start yielding only None when chans is empty
-#[derive(Debug)]
-pub struct MergedChannels<T> {
-    /// Set of channels to merge input from
+/// Items that compare equal are yielded in the order of the channels they came from in the vec
+/// passed to the constructor. Because ids are never renumbered this doesn't depend on which
+/// channels happen to be exhausted first, so merging the same inputs always produces the same
+/// output.
+///
+/// Start yielding only None when every channel is exhausted
+pub struct MergedChannels<T, C = fn(&T, &T) -> Ordering> {
+    /// Set of channels to merge input from, indexed by channel id
     chans: Vec<Receiver<T>>,
-    /// Sorted list of head items already grabbed from other channels and the index of that channel
-    /// in chans
-    head_items: Vec<(T, usize)>,
-    /// the index of the source chan of the previously yielded head_item
-    last_picked: Option<usize>,
+    /// Head items and which channels still owe one
+    merge: Merge<T, C>,
 }
 
-impl<T> Iterator for MergedChannels<T>
+impl<T, C> Debug for MergedChannels<T, C>
 where
-    T: Ord + Debug,
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MergedChannels")
+            .field("chans", &self.chans)
+            .field("merge", &self.merge)
+            .finish()
+    }
+}
+
+impl<T, C> Iterator for MergedChannels<T, C>
+where
+    T: Debug,
+    C: FnMut(&T, &T) -> Ordering,
 {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
         trace!("enter");
-        if self.chans.is_empty() {
-            return None;
-        }
-
-        if let Some(id) = self.last_picked {
+        while let Some(&id) = self.merge.wanted().last() {
             self.receive_from(id);
-        } else {
-            self.receive_from_all();
         }
 
-        self.get_next_head_item()
+        self.merge.pop()
     }
 }
 
@@ -57,88 +74,664 @@ impl<T> MergedChannels<T>
 where
     T: Ord + Debug,
 {
-    /// Construct a merged channels
+    /// Construct a merged channels ordered by the natural ordering of T
     #[tracing::instrument]
     pub fn new(chans: Vec<Receiver<T>>) -> Self {
+        trace!("enter");
+        MergedChannels::by(chans, T::cmp as fn(&T, &T) -> Ordering)
+    }
+}
+
+impl<T> MergedChannels<T>
+where
+    T: Debug,
+{
+    /// Construct a merged channels ordered by the key extracted from each item
+    ///
+    /// The key is extracted every time two items are compared, so it should be cheap to compute.
+    pub fn by_key<K, F>(
+        chans: Vec<Receiver<T>>,
+        mut f: F,
+    ) -> MergedChannels<T, impl FnMut(&T, &T) -> Ordering>
+    where
+        K: Ord,
+        F: FnMut(&T) -> K,
+    {
+        MergedChannels::by(chans, move |a: &T, b: &T| f(a).cmp(&f(b)))
+    }
+}
+
+impl<T, C> MergedChannels<T, C>
+where
+    T: Debug,
+    C: FnMut(&T, &T) -> Ordering,
+{
+    /// Construct a merged channels ordered by the comparator cmp
+    pub fn by(chans: Vec<Receiver<T>>, cmp: C) -> Self {
         trace!("enter");
         Self {
+            merge: Merge::new(chans.len(), cmp),
             chans,
-            head_items: vec![],
-            last_picked: None,
         }
     }
 
-    /// pop the lowest item in the vec and save the id of the channel that item came from
-    #[tracing::instrument]
-    fn get_next_head_item(&mut self) -> Option<T> {
-        trace!("enter");
-        self.head_items.pop().map(|(item, last_picked)| {
-            self.last_picked = Some(last_picked);
-
-            item
-        })
+    /// Turn this into a merge suitable for channels that are still being written to, like the
+    /// output of `tail -F`
+    ///
+    /// See FollowedChannels for how lateness is used.
+    pub fn follow(self, lateness: Duration) -> FollowedChannels<T, C> {
+        let now = Instant::now();
+        FollowedChannels {
+            wanted_since: vec![now; self.chans.len()],
+            chans: self.chans,
+            merge: self.merge,
+            lateness,
+            watermark: None,
+        }
     }
 
-    /// Receive the next item from chan id and insert that into head_items
+    /// Receive the next item from chan id and hand it to the merge
     #[tracing::instrument]
     fn receive_from(&mut self, id: usize) {
         trace!("enter");
         match self.chans[id].recv() {
-            Ok(item) => self.sorted_insert((item, id)),
+            Ok(item) => self.merge.received(id, item),
             Err(e) => {
                 debug!(message = "channel exhausted", ?id, ?e);
-                self.remove_channel(id);
+                self.merge.exhausted(id);
             }
         }
     }
+}
+
+/// Merged set of channels that keeps yielding while some channels are quiet
+///
+/// MergedChannels waits on the channel of the last yielded item before yielding anything else,
+/// which for live sources means one quiet channel stalls the whole merge. Instead this yields
+/// the smallest head_item once every channel has either produced its next item or been waited on
+/// for longer than the lateness window. Each channel gets its own window, so a channel that has
+/// been quiet for a long time doesn't stop the merge from waiting on the others.
+///
+/// The largest item yielded so far is the watermark. An item from a quiet channel that arrives
+/// after the merge has moved past it sorts before the watermark, it is yielded as soon as
+/// possible and flagged as late instead of being silently placed out of order.
+pub struct FollowedChannels<T, C = fn(&T, &T) -> Ordering> {
+    /// Set of channels to merge input from, indexed by channel id
+    chans: Vec<Receiver<T>>,
+    /// Head items and which channels still owe one
+    merge: Merge<T, C>,
+    /// How long to wait on a quiet channel before moving on without it
+    lateness: Duration,
+    /// When each channel was last asked for a new head item, indexed by channel id
+    wanted_since: Vec<Instant>,
+    /// The largest item yielded so far
+    watermark: Option<T>,
+}
+
+/// An item yielded by FollowedChannels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Followed<T> {
+    pub item: T,
+    /// The item sorts before an item that was already yielded
+    pub late: bool,
+}
+
+impl<T, C> Debug for FollowedChannels<T, C>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FollowedChannels")
+            .field("chans", &self.chans)
+            .field("merge", &self.merge)
+            .field("lateness", &self.lateness)
+            .field("watermark", &self.watermark)
+            .finish()
+    }
+}
 
-    /// Receive one item for each channel to fill up head_items
-    fn receive_from_all(&mut self) {
+impl<T, C> Iterator for FollowedChannels<T, C>
+where
+    T: Clone + Debug,
+    C: FnMut(&T, &T) -> Ordering,
+{
+    type Item = Followed<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
         trace!("enter");
-        for id in 0..self.chans.len() {
-            self.receive_from(id);
+        loop {
+            self.receive_ready();
+
+            let now = Instant::now();
+            let lateness = self.lateness;
+            let wanted_since = &self.wanted_since;
+            // the last moment any wanted channel is still owed a wait
+            let patience = self
+                .merge
+                .wanted()
+                .iter()
+                .map(|&id| wanted_since[id] + lateness)
+                .max();
+
+            match patience {
+                Some(until) if until > now => self.wait_for_wanted(Some(until - now)),
+                _ => {
+                    if let Some((item, id)) = self.merge.pop_early() {
+                        self.wanted_since[id] = Instant::now();
+                        return Some(self.flag(item));
+                    }
+
+                    if self.merge.wanted().is_empty() {
+                        return None;
+                    }
+
+                    // everything still wanted is quiet and there's nothing to yield meanwhile
+                    self.wait_for_wanted(None);
+                }
+            }
         }
     }
+}
 
-    /// Insert item into head_items in sorted order
-    #[tracing::instrument]
-    fn sorted_insert(&mut self, item: (T, usize)) {
-        trace!("enter");
-        let ind = match self
-            .head_items
-            .binary_search_by(|probe| match probe.cmp(&item) {
-                Ordering::Less => Ordering::Greater,
-                Ordering::Greater => Ordering::Less,
-                item => item,
-            }) {
-            Ok(_id) => unreachable!(), // exact match exists. should never have duplicate ids
-            Err(id) => id,             // insert location to maintain sort order
+impl<T, C> FollowedChannels<T, C>
+where
+    T: Clone + Debug,
+    C: FnMut(&T, &T) -> Ordering,
+{
+    /// Take whatever the wanted channels already have without blocking
+    fn receive_ready(&mut self) {
+        let mut ind = 0;
+        while let Some(&id) = self.merge.wanted().get(ind) {
+            match self.chans[id].try_recv() {
+                Ok(item) => self.merge.received(id, item),
+                Err(TryRecvError::Disconnected) => {
+                    debug!(message = "channel exhausted", ?id);
+                    self.merge.exhausted(id);
+                }
+                Err(TryRecvError::Empty) => ind += 1,
+            }
+        }
+    }
+
+    /// Block until one of the wanted channels is ready or timeout passes
+    fn wait_for_wanted(&self, timeout: Option<Duration>) {
+        let mut select = Select::new();
+        for &id in self.merge.wanted() {
+            let _ = select.recv(&self.chans[id]);
+        }
+
+        match timeout {
+            Some(timeout) => {
+                let _ = select.ready_timeout(timeout);
+            }
+            None => {
+                let _ = select.ready();
+            }
+        }
+    }
+
+    /// Flag item as late if it sorts before the watermark, otherwise make it the new watermark
+    fn flag(&mut self, item: T) -> Followed<T> {
+        let late = match &self.watermark {
+            Some(watermark) => self.merge.cmp(&item, watermark) == Ordering::Less,
+            None => false,
         };
 
-        self.head_items.insert(ind, item);
+        if !late {
+            self.watermark = Some(item.clone());
+        }
+
+        Followed { item, late }
     }
+}
 
-    /// Remove a channel that is exhausted from the set of channels and adjust the id of the
-    /// head_item that swapped with the removed channel to indicate its new location in the chans
-    /// vector
-    #[tracing::instrument]
-    fn remove_channel(&mut self, id: usize) {
-        trace!(message = "removing id", ?id, ?self);
-        let _ = self.chans.swap_remove(id);
+/// Representation of a merged set of iterators as an iterator
+///
+/// The same merge as MergedChannels for sources that can be read synchronously, such as log files
+/// already collected from each node, without a thread per source to feed a channel. Items are
+/// pulled from each iterator on demand, one head_item per iterator at a time.
+pub struct MergedIters<I, C = fn(&<I as Iterator>::Item, &<I as Iterator>::Item) -> Ordering>
+where
+    I: Iterator,
+{
+    /// Set of iterators to merge input from, indexed by iterator id
+    iters: Vec<I>,
+    /// Head items and which iterators still owe one
+    merge: Merge<I::Item, C>,
+}
+
+impl<I, C> Debug for MergedIters<I, C>
+where
+    I: Iterator,
+    I::Item: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MergedIters")
+            .field("iters", &self.iters.len())
+            .field("merge", &self.merge)
+            .finish()
+    }
+}
 
-        let old_id = self.chans.len();
+impl<I, C> Iterator for MergedIters<I, C>
+where
+    I: Iterator,
+    C: FnMut(&I::Item, &I::Item) -> Ordering,
+{
+    type Item = I::Item;
 
-        if let Some(dirty_head_item) = self.head_items.iter_mut().find(|item| item.1 == old_id) {
-            dirty_head_item.1 = id;
-        };
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(&id) = self.merge.wanted().last() {
+            match self.iters[id].next() {
+                Some(item) => self.merge.received(id, item),
+                None => self.merge.exhausted(id),
+            }
+        }
+
+        self.merge.pop()
+    }
+}
+
+impl<I> MergedIters<I>
+where
+    I: Iterator,
+    I::Item: Ord,
+{
+    /// Construct a merged iterators ordered by the natural ordering of the items
+    pub fn new(iters: Vec<I>) -> Self {
+        MergedIters::by(iters, I::Item::cmp as fn(&I::Item, &I::Item) -> Ordering)
+    }
+}
+
+impl<R> MergedIters<ReadLines<R>>
+where
+    R: BufRead,
+{
+    /// Construct a merged iterators over the lines of each reader, ordered by their bytes
+    ///
+    /// Lines are yielded without their line ending. A read error is yielded as soon as it happens
+    /// and ends that reader, the other readers carry on.
+    pub fn lines(readers: Vec<R>) -> Self {
+        MergedIters::by(
+            readers.into_iter().map(ReadLines::new).collect(),
+            errors_first as fn(&io::Result<Vec<u8>>, &io::Result<Vec<u8>>) -> Ordering,
+        )
+    }
+}
+
+impl<I> MergedIters<I>
+where
+    I: Iterator,
+{
+    /// Construct a merged iterators ordered by the key extracted from each item
+    ///
+    /// The key is extracted every time two items are compared, so it should be cheap to compute.
+    pub fn by_key<K, F>(
+        iters: Vec<I>,
+        mut f: F,
+    ) -> MergedIters<I, impl FnMut(&I::Item, &I::Item) -> Ordering>
+    where
+        K: Ord,
+        F: FnMut(&I::Item) -> K,
+    {
+        MergedIters::by(iters, move |a: &I::Item, b: &I::Item| f(a).cmp(&f(b)))
+    }
+}
+
+impl<I, C> MergedIters<I, C>
+where
+    I: Iterator,
+    C: FnMut(&I::Item, &I::Item) -> Ordering,
+{
+    /// Construct a merged iterators ordered by the comparator cmp
+    pub fn by(iters: Vec<I>, cmp: C) -> Self {
+        Self {
+            merge: Merge::new(iters.len(), cmp),
+            iters,
+        }
+    }
+}
+
+/// Iterator over the lines of a reader as bytes, which stops after the first error
+#[derive(Debug)]
+pub struct ReadLines<R> {
+    read: Option<R>,
+}
+
+impl<R> ReadLines<R> {
+    fn new(read: R) -> Self {
+        Self { read: Some(read) }
+    }
+}
+
+impl<R> Iterator for ReadLines<R>
+where
+    R: BufRead,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let read = self.read.as_mut()?;
+
+        let mut line = vec![];
+        match read.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                self.read = None;
+                None
+            }
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    let _ = line.pop();
+                    if line.last() == Some(&b'\r') {
+                        let _ = line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Err(e) => {
+                self.read = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Order lines by their bytes, but put errors first so they are reported as soon as they happen
+fn errors_first(a: &io::Result<Vec<u8>>, b: &io::Result<Vec<u8>>) -> Ordering {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a.cmp(b),
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => Ordering::Equal,
+    }
+}
+
+/// Representation of a merged set of streams as a stream
+///
+/// The async counterpart of MergedChannels with the same sortedness assumption and the same
+/// ordering guarantees, but it polls its sources instead of blocking on them. Works with any
+/// `Unpin` stream, including tokio mpsc receivers.
+///
+/// Every stream still owing a head_item is polled on each wakeup, so slow streams are waited on
+/// concurrently rather than one after another.
+pub struct MergedStreams<S, C = fn(&<S as Stream>::Item, &<S as Stream>::Item) -> Ordering>
+where
+    S: Stream,
+{
+    /// Set of streams to merge input from, indexed by stream id
+    streams: Vec<S>,
+    /// Head items and which streams still owe one
+    merge: Merge<S::Item, C>,
+}
+
+impl<S, C> Debug for MergedStreams<S, C>
+where
+    S: Stream,
+    S::Item: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MergedStreams")
+            .field("streams", &self.streams.len())
+            .field("merge", &self.merge)
+            .finish()
+    }
+}
+
+// Nothing in a merged streams is ever pinned in place, each stream is required to be Unpin and is
+// polled through its own Pin::new
+impl<S, C> Unpin for MergedStreams<S, C> where S: Stream {}
+
+impl<S, C> Stream for MergedStreams<S, C>
+where
+    S: Stream + Unpin,
+    S::Item: Debug,
+    C: FnMut(&S::Item, &S::Item) -> Ordering,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        trace!("enter");
+        let this = self.get_mut();
+
+        let mut ind = 0;
+        while let Some(&id) = this.merge.wanted().get(ind) {
+            match Pin::new(&mut this.streams[id]).poll_next(cx) {
+                Poll::Ready(Some(item)) => this.merge.received(id, item),
+                Poll::Ready(None) => {
+                    debug!(message = "stream exhausted", ?id);
+                    this.merge.exhausted(id);
+                }
+                Poll::Pending => ind += 1,
+            }
+        }
+
+        if this.merge.wanted().is_empty() {
+            Poll::Ready(this.merge.pop())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S> MergedStreams<S>
+where
+    S: Stream,
+    S::Item: Ord,
+{
+    /// Construct a merged streams ordered by the natural ordering of the items
+    pub fn new(streams: Vec<S>) -> Self {
+        MergedStreams::by(
+            streams,
+            S::Item::cmp as fn(&S::Item, &S::Item) -> Ordering,
+        )
+    }
+}
+
+impl<S> MergedStreams<S>
+where
+    S: Stream,
+{
+    /// Construct a merged streams ordered by the key extracted from each item
+    ///
+    /// The key is extracted every time two items are compared, so it should be cheap to compute.
+    pub fn by_key<K, F>(
+        streams: Vec<S>,
+        mut f: F,
+    ) -> MergedStreams<S, impl FnMut(&S::Item, &S::Item) -> Ordering>
+    where
+        K: Ord,
+        F: FnMut(&S::Item) -> K,
+    {
+        MergedStreams::by(streams, move |a: &S::Item, b: &S::Item| f(a).cmp(&f(b)))
+    }
+}
+
+impl<S, C> MergedStreams<S, C>
+where
+    S: Stream,
+    C: FnMut(&S::Item, &S::Item) -> Ordering,
+{
+    /// Construct a merged streams ordered by the comparator cmp
+    pub fn by(streams: Vec<S>, cmp: C) -> Self {
+        Self {
+            merge: Merge::new(streams.len(), cmp),
+            streams,
+        }
+    }
+}
+
+/// The merge logic shared by every kind of merged source
+///
+/// Sources are identified by their position in the vec passed to the constructor of the merged
+/// type. The merge only tracks which sources it still needs a head_item from before the smallest
+/// head_item can be yielded, the merged types are responsible for actually receiving from those
+/// sources and reporting back with received or exhausted.
+struct Merge<T, C> {
+    /// Min heap of head items already grabbed from sources and the id of that source
+    head_items: HeadItems<T, C>,
+    /// Ids of the sources that have to provide a head item or be exhausted before the next pop.
+    /// Starts as every source, afterwards it's just the source of the previously popped item
+    wanted: Vec<usize>,
+}
+
+impl<T, C> Debug for Merge<T, C>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Merge")
+            .field("head_items", &self.head_items)
+            .field("wanted", &self.wanted)
+            .finish()
+    }
+}
+
+impl<T, C> Merge<T, C>
+where
+    C: FnMut(&T, &T) -> Ordering,
+{
+    fn new(sources: usize, cmp: C) -> Self {
+        Self {
+            head_items: HeadItems::with_capacity(sources, cmp),
+            // reversed so sources are received from in order when taken from the back
+            wanted: (0..sources).rev().collect(),
+        }
+    }
+
+    /// Sources that still owe a head item
+    fn wanted(&self) -> &[usize] {
+        &self.wanted
+    }
+
+    fn unwant(&mut self, id: usize) {
+        if let Some(ind) = self.wanted.iter().rposition(|&wanted| wanted == id) {
+            let _ = self.wanted.remove(ind);
+        }
+    }
+
+    /// Record the next item from source id
+    fn received(&mut self, id: usize, item: T) {
+        self.unwant(id);
+        self.head_items.push((item, id));
+    }
+
+    /// Record that source id has no more items, it will never be wanted again
+    fn exhausted(&mut self, id: usize) {
+        self.unwant(id);
+    }
+
+    /// pop the lowest head item and want another from the source it came from
+    ///
+    /// Only meaningful once nothing is wanted, returns None once every source is exhausted
+    fn pop(&mut self) -> Option<T> {
+        debug_assert!(self.wanted.is_empty());
+        self.pop_early().map(|(item, _)| item)
+    }
+
+    /// pop the lowest head item even if some sources still owe one, along with the id of the
+    /// source it came from
+    ///
+    /// Whatever the sources that were given up on produce later may sort before the popped item.
+    fn pop_early(&mut self) -> Option<(T, usize)> {
+        self.head_items.pop().map(|(item, id)| {
+            self.wanted.push(id);
+
+            (item, id)
+        })
+    }
+
+    /// Compare two items with the merge's ordering
+    fn cmp(&mut self, a: &T, b: &T) -> Ordering {
+        (self.head_items.cmp)(a, b)
+    }
+}
+
+/// Binary min heap of head items and the id of the source they came from
+///
+/// Items are ordered by cmp and ties are broken by source id. This is hand rolled rather than a
+/// std BinaryHeap because the ordering is supplied at runtime instead of by an Ord impl.
+struct HeadItems<T, C> {
+    items: Vec<(T, usize)>,
+    cmp: C,
+}
+
+impl<T, C> Debug for HeadItems<T, C>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.items).finish()
+    }
+}
+
+impl<T, C> HeadItems<T, C>
+where
+    C: FnMut(&T, &T) -> Ordering,
+{
+    fn with_capacity(capacity: usize, cmp: C) -> Self {
+        Self {
+            items: Vec::with_capacity(capacity),
+            cmp,
+        }
+    }
 
-        trace!(message = "removed id", ?id, ?self);
+    /// Whether the item at index a sorts before the item at index b
+    fn less(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.items[a], &self.items[b]);
+        (self.cmp)(&a.0, &b.0).then(a.1.cmp(&b.1)) == Ordering::Less
+    }
+
+    fn push(&mut self, item: (T, usize)) {
+        self.items.push(item);
+
+        let mut child = self.items.len() - 1;
+        while child > 0 {
+            let parent = (child - 1) / 2;
+            if !self.less(child, parent) {
+                break;
+            }
+            self.items.swap(child, parent);
+            child = parent;
+        }
+    }
+
+    fn pop(&mut self) -> Option<(T, usize)> {
+        if self.items.is_empty() {
+            return None;
+        }
+
+        let item = self.items.swap_remove(0);
+
+        let len = self.items.len();
+        let mut parent = 0;
+        loop {
+            let left = 2 * parent + 1;
+            let right = left + 1;
+            if left >= len {
+                break;
+            }
+
+            let smallest = if right < len && self.less(right, left) {
+                right
+            } else {
+                left
+            };
+
+            if !self.less(smallest, parent) {
+                break;
+            }
+            self.items.swap(smallest, parent);
+            parent = smallest;
+        }
+
+        Some(item)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cmp::Reverse;
 
     #[test]
     fn happy_path() {
@@ -171,4 +764,164 @@ mod tests {
             info!(%item, %id);
         }
     }
+
+    /// Item that only compares by key so ties between channels are visible in the output
+    #[derive(Debug)]
+    struct Keyed(u32, &'static str);
+
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Keyed {}
+
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn ties_broken_by_channel_order() {
+        let inputs = vec![
+            vec![Keyed(1, "a1"), Keyed(3, "a3")],
+            vec![Keyed(1, "b1")],
+            vec![Keyed(0, "c0"), Keyed(1, "c1"), Keyed(3, "c3")],
+            vec![Keyed(3, "d3")],
+        ];
+
+        let chans = inputs
+            .into_iter()
+            .map(|items| {
+                let (s, r) = crossbeam::channel::unbounded();
+                for item in items {
+                    s.send(item).unwrap();
+                }
+                r
+            })
+            .collect();
+
+        let merged: Vec<_> = MergedChannels::new(chans).map(|item| item.1).collect();
+
+        assert_eq!(vec!["c0", "a1", "b1", "c1", "a3", "c3", "d3"], merged);
+    }
+
+    #[test]
+    fn key_and_comparator_constructors() {
+        let chans = || {
+            [vec![(5, "a"), (1, "b")], vec![(4, "c"), (2, "d"), (0, "e")]]
+                .iter()
+                .map(|items| {
+                    let (s, r) = crossbeam::channel::unbounded();
+                    for item in items {
+                        s.send(*item).unwrap();
+                    }
+                    r
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let merged: Vec<_> = MergedChannels::by_key(chans(), |item| Reverse(item.0))
+            .map(|item| item.1)
+            .collect();
+        assert_eq!(vec!["a", "c", "d", "b", "e"], merged);
+
+        let merged: Vec<_> = MergedChannels::by(chans(), |a: &(i32, &str), b: &(i32, &str)| {
+            b.0.cmp(&a.0)
+        })
+        .map(|item| item.1)
+        .collect();
+        assert_eq!(vec!["a", "c", "d", "b", "e"], merged);
+    }
+
+    #[test]
+    fn follow_quiet_channel() {
+        let (s1, r1) = crossbeam::channel::unbounded();
+        let (s2, r2) = crossbeam::channel::unbounded();
+
+        s1.send(2).unwrap();
+
+        let mut m = MergedChannels::new(vec![r1, r2]).follow(Duration::from_millis(20));
+
+        // r2 is quiet but still open, the merge has to give up on it rather than block
+        assert_eq!(Some(Followed { item: 2, late: false }), m.next());
+
+        s2.send(1).unwrap();
+        assert_eq!(Some(Followed { item: 1, late: true }), m.next());
+
+        s2.send(3).unwrap();
+        s1.send(4).unwrap();
+        drop(s1);
+        drop(s2);
+        assert_eq!(Some(Followed { item: 3, late: false }), m.next());
+        assert_eq!(Some(Followed { item: 4, late: false }), m.next());
+        assert_eq!(None, m.next());
+    }
+
+    #[test]
+    fn follow_waits_on_active_channels() {
+        let (quiet_send, quiet) = crossbeam::channel::unbounded::<u32>();
+        let (sa, ra) = crossbeam::channel::unbounded();
+        let (sb, rb) = crossbeam::channel::unbounded();
+
+        sa.send(10).unwrap();
+        sb.send(20).unwrap();
+
+        let mut m = MergedChannels::new(vec![quiet, ra, rb]).follow(Duration::from_millis(500));
+
+        assert_eq!(Some(Followed { item: 10, late: false }), m.next());
+
+        // the quiet channel is long past its window, a still gets the whole of its own
+        let delayed = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            sa.send(15).unwrap();
+        });
+        assert_eq!(Some(Followed { item: 15, late: false }), m.next());
+        delayed.join().unwrap();
+
+        drop(sb);
+        drop(quiet_send);
+        assert_eq!(Some(Followed { item: 20, late: false }), m.next());
+        assert_eq!(None, m.next());
+    }
+
+    #[test]
+    fn merged_readers() {
+        let a = io::Cursor::new("2020-01-01 a\n2020-01-03 a\r\n");
+        let b = io::Cursor::new("2020-01-02 b\n2020-01-04 b");
+
+        let merged: Vec<_> = MergedIters::lines(vec![a, b])
+            .map(|line| String::from_utf8(line.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(
+            vec!["2020-01-01 a", "2020-01-02 b", "2020-01-03 a", "2020-01-04 b"],
+            merged
+        );
+    }
+
+    #[test]
+    fn merged_streams() {
+        use futures::stream::{self, StreamExt};
+
+        let (s1, r1) = futures::channel::mpsc::unbounded();
+        for item in &[1, 4, 4, 9] {
+            s1.unbounded_send(*item).unwrap();
+        }
+        drop(s1);
+
+        let streams = vec![r1.boxed(), stream::iter(vec![2, 4, 8]).boxed()];
+        let merged: Vec<_> =
+            futures::executor::block_on(MergedStreams::new(streams).collect::<Vec<_>>());
+
+        assert_eq!(vec![1, 2, 4, 4, 4, 8, 9], merged);
+    }
 }
@@ -5,18 +5,31 @@
 #[macro_use]
 extern crate tracing;
 
-use core::fmt::Debug;
-use crossbeam::channel::{Receiver, Sender};
-use failure::Error;
-use regex::Regex;
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use core::fmt::{self, Debug};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Select, Sender};
+use failure::{Error, Fail};
+use regex::bytes::Regex;
+use scale::merged_chan::{Followed, MergedChannels};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::{self, prelude::*, BufRead, BufReader};
-use std::ops::Deref;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::ops::{Deref, Range};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::{
     clap::{AppSettings, Shell},
     StructOpt,
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 #[derive(StructOpt, Debug)]
 #[structopt(raw(
@@ -37,6 +50,95 @@ struct Cli {
     #[structopt(short = "m", long = "merge")]
     merge: bool,
 
+    /// With --merge or --follow, stop waiting on a node that has gone quiet after this long, e.g.
+    /// `2s`, instead of holding back every other node's output until it says something
+    #[structopt(long = "lateness", parse(try_from_str = "humantime::parse_duration"))]
+    lateness: Option<Duration>,
+
+    /// With --lateness or --follow, mark lines that arrived too late to be merged in order
+    #[structopt(long = "flag-late")]
+    flag_late: bool,
+
+    /// With --merge or --follow, how to parse the timestamps lines are sorted by: `iso8601`,
+    /// `rfc3339`, `syslog`, `epoch`, `epoch-millis`, or a strftime format like
+    /// `%d/%b/%Y:%H:%M:%S %z`. Lines without one are sorted along with the line before them
+    #[structopt(long = "timestamp-format", default_value = "iso8601")]
+    timestamp_format: TimestampFormat,
+
+    /// With --merge or --follow, regex finding the timestamp when it isn't at the start of the
+    /// line, e.g. `\[([^]]+)\]`. Its first capture group is used if it has one, otherwise the
+    /// whole match
+    #[structopt(long = "timestamp-regex")]
+    timestamp_regex: Option<Regex>,
+
+    /// With --merge or --follow, rewrite every timestamp in this zone, e.g. `UTC`, `local`,
+    /// `+05:30` or `America/New_York`, so nodes logging in different zones read the same
+    #[structopt(long = "output-timezone")]
+    output_timezone: Option<Zone>,
+
+    /// With --merge or --follow, measure how far each node's clock is off from ours before
+    /// starting and correct its timestamps by that much. The offsets are shown in the summary
+    #[structopt(long = "correct-skew")]
+    correct_skew: bool,
+
+    /// With --merge or --follow, hold back this much of each node's output, e.g. `2s` or `100`
+    /// lines, so lines that are a little out of order are sorted before they are merged
+    #[structopt(long = "reorder-window")]
+    reorder_window: Option<ReorderWindow>,
+
+    /// With --merge or --follow, warn about each line still out of order after any
+    /// --reorder-window instead of only counting them in the summary
+    #[structopt(long = "report-unsorted")]
+    report_unsorted: bool,
+
+    /// With --merge or --follow, how to show the lines continuing a timestamped line, like a
+    /// stack trace: `restamp` them with its timestamp or `indent` them under it
+    #[structopt(
+        long = "continuation",
+        default_value = "restamp",
+        raw(possible_values = "&[\"restamp\", \"indent\"]")
+    )]
+    continuation: Continuation,
+
+    /// With --merge or --follow, width of the node column in terminal columns, longer idents are
+    /// cut short. Defaults to fitting the longest ident of the selected nodes
+    #[structopt(long = "ident-width")]
+    ident_width: Option<usize>,
+
+    /// Lay out every output line with this template, e.g. `{ts} {node:<20} {stream} {line}`.
+    /// Fields are ts, node, main_ip, backplane_ip, hostname, stream, lineno and line, and take an
+    /// alignment and width like `{node:>16}`. Only merged lines have a {ts}, their {line} is
+    /// whatever follows it. {lineno} counts stdout and stderr lines separately
+    #[structopt(long = "format")]
+    format: Option<Template>,
+
+    /// Write lines as `text`, or as `json` with an object per line followed by one summing up
+    /// how each node went
+    #[structopt(
+        long = "output",
+        default_value = "text",
+        raw(possible_values = "&[\"text\", \"json\"]")
+    )]
+    output: OutputMode,
+
+    /// Also save each node's output in DIR, stdout in `<ident>.log`, stderr in `<ident>.err` and
+    /// the exit code in `<ident>.rc`
+    #[structopt(long = "output-dir", parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// Print lines from all nodes as soon as they arrive, prefixed with the node they came from
+    #[structopt(short = "s", long = "stream", conflicts_with = "merge")]
+    stream: bool,
+
+    /// Follow the file at PATH on every node like `tail -F`, merging new lines by timestamp as
+    /// they arrive. Dropped connections resume from the last line seen, Ctrl-C stops every tail
+    #[structopt(
+        long = "follow",
+        parse(from_os_str),
+        raw(conflicts_with_all = "&[\"stream\", \"command\", \"parallel\", \"serial\"]")
+    )]
+    follow: Option<PathBuf>,
+
     /// Don't indent output.
     #[structopt(long = "no-indent")]
     no_indent: bool,
@@ -45,6 +147,48 @@ struct Cli {
     #[structopt(long = "timeout", default_value = "5")]
     timeout: u32,
 
+    /// Kill the command on a node if it is still running after this long, e.g. `30s` or `5m`
+    #[structopt(long = "deadline", parse(try_from_str = "humantime::parse_duration"))]
+    deadline: Option<Duration>,
+
+    /// Run on at most N nodes at once
+    #[structopt(long = "parallel", conflicts_with = "serial")]
+    parallel: Option<usize>,
+
+    /// Run on one node at a time, the same as `--parallel 1`
+    #[structopt(long = "serial")]
+    serial: bool,
+
+    /// Don't start the command on any more nodes once it has failed on one
+    #[structopt(long = "stop-on-failure")]
+    stop_on_failure: bool,
+
+    /// Exit non-zero if the command fails on any node, or only if it fails on all of them
+    #[structopt(
+        long = "fail-on",
+        default_value = "any",
+        raw(possible_values = "&[\"any\", \"all\"]")
+    )]
+    fail_on: FailOn,
+
+    /// What to do with remote stderr: `merge` it into the output with a marker, `prefix` it with
+    /// the node and write it to local stderr, or `drop` it
+    #[structopt(
+        long = "stderr",
+        default_value = "prefix",
+        raw(possible_values = "&[\"merge\", \"prefix\", \"drop\"]")
+    )]
+    stderr: StderrMode,
+
+    /// What to do with output that isn't valid utf-8: replace the bad bytes (`lossy`), pass them
+    /// through untouched (`raw`), or escape them as `\xNN` (`hex`)
+    #[structopt(
+        long = "encoding",
+        default_value = "lossy",
+        raw(possible_values = "&[\"lossy\", \"raw\", \"hex\"]")
+    )]
+    encoding: Encoding,
+
     /// Generate a completion file
     #[structopt(
         long = "generate-completions",
@@ -56,19 +200,149 @@ struct Cli {
     #[structopt(long = "node", raw(number_of_values = "1"))]
     nodes: Vec<Node>,
 
+    /// Only run on inventory nodes with this tag, e.g. `role=storage`. May be repeated, nodes must
+    /// have every tag
+    #[structopt(long = "only", raw(number_of_values = "1"))]
+    only: Vec<TagSelector>,
+
+    /// Only run on inventory nodes matching this pattern, e.g. `node[01-08]` or `10.0.0.*`. May
+    /// be repeated, nodes must match any of them
+    #[structopt(long = "nodes", raw(number_of_values = "1"))]
+    select: Vec<NodePattern>,
+
+    /// Don't run on inventory nodes matching this pattern. May be repeated
+    #[structopt(long = "exclude", raw(number_of_values = "1"))]
+    exclude: Vec<NodePattern>,
+
+    /// Only run on the first N selected inventory nodes
+    #[structopt(long = "first")]
+    first: Option<usize>,
+
+    /// Only run on N randomly chosen selected inventory nodes
+    #[structopt(long = "random", conflicts_with = "first")]
+    random: Option<usize>,
+
     /// Command to run on each node
     command: Vec<String>,
 }
 
+impl Cli {
+    /// Whether the output is merged by timestamp, which --follow always does
+    fn merging(&self) -> bool {
+        self.merge || self.follow.is_some()
+    }
+
+    /// How long the merge waits on a quiet node before moving on without it, None if it waits
+    /// for as long as it takes
+    fn merge_lateness(&self) -> Option<Duration> {
+        self.lateness
+            .or_else(|| self.follow.as_ref().map(|_| DEFAULT_FOLLOW_LATENESS))
+    }
+
+    /// Reject combinations of options that clap can't express: ones that would be silently
+    /// ignored without --merge or --follow, as --follow conflicts with the command, --flag-late
+    /// without a lateness window, which --follow has by default, and --format with --output json,
+    /// as clap counts the default --output as given
+    fn check(&self) -> Result<(), Error> {
+        let merge_only = [
+            ("--lateness", self.lateness.is_some()),
+            ("--correct-skew", self.correct_skew),
+            ("--reorder-window", self.reorder_window.is_some()),
+            ("--report-unsorted", self.report_unsorted),
+            ("--output-timezone", self.output_timezone.is_some()),
+            ("--timestamp-regex", self.timestamp_regex.is_some()),
+            ("--ident-width", self.ident_width.is_some()),
+        ];
+
+        if self.format.is_some() && self.output == OutputMode::Json {
+            failure::bail!("--format can't be used with --output json");
+        }
+
+        if self.flag_late && self.merge_lateness().is_none() {
+            failure::bail!("--flag-late needs --lateness or --follow");
+        }
+
+        match merge_only.iter().find(|(_, given)| *given) {
+            Some((name, _)) if !self.merging() => {
+                failure::bail!("{} needs --merge or --follow", name)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How often to check whether a child with a deadline, or being followed, has exited
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Marker inserted into lines that came from remote stderr when they share the output stream
+const STDERR_MARKER: &str = "[stderr]";
+
+/// Marker prepended to merged lines that arrived after later lines were already printed
+const LATE_MARKER: &str = "[late]";
+
+/// How long --follow waits on a quiet node when no --lateness is given
+const DEFAULT_FOLLOW_LATENESS: Duration = Duration::from_secs(1);
+
+/// Shortest time a node must be quiet before its pending record is sent, so a tiny --lateness
+/// doesn't have every quiet node spinning
+const MIN_IDLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long --follow waits before reconnecting to a node whose connection dropped
+const FOLLOW_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How many times measure_skew samples the clock of each node
+const SKEW_SAMPLES: usize = 5;
+
+/// Most numbers a numeric range like `[01-08]` in a node pattern may expand to
+const MAX_PATTERN_RANGE: u64 = 10_000;
+
+/// Offset stored in a FollowPosition before the remote side has said where it started
+const UNKNOWN_OFFSET: u64 = u64::MAX;
+
+/// Set by the Ctrl-C handler in --follow mode to tear down every remote tail and finish up
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
 lazy_static::lazy_static! {
     static ref ARGS: Cli = Cli::from_args();
-    static ref DATE: Regex = Regex::new("^....-..-.....:..:..\\.......").unwrap();
+    static ref TIMESTAMP: Regex = match &ARGS.timestamp_regex {
+        Some(regex) => regex.clone(),
+        None => Regex::new(&format!("^{}", ARGS.timestamp_format.pattern())).unwrap(),
+    };
 }
 
-#[derive(Debug, Clone)]
+/// A single node from the inventory
+#[derive(Debug, Clone, Deserialize)]
 struct Node {
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    uuid: Option<String>,
+    /// Address we ssh to
     main_ip: String,
+    /// Address used to identify the node in output
     backplane_ip: String,
+    /// Remote user to ssh as instead of the ssh default
+    #[serde(default)]
+    user: Option<String>,
+    /// Remote port to ssh to instead of the ssh default
+    #[serde(default)]
+    port: Option<u16>,
+    /// Arbitrary labels such as `role = "storage"` or `rack = "3"`
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    /// Zone the node logs in, for timestamps without an offset of their own. Defaults to UTC
+    #[serde(default)]
+    timezone: Option<Zone>,
+}
+
+impl Node {
+    /// The `[user@]host` argument to pass to ssh
+    fn ssh_destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.main_ip),
+            None => self.main_ip.clone(),
+        }
+    }
 }
 
 impl std::str::FromStr for Node {
@@ -77,222 +351,2937 @@ impl std::str::FromStr for Node {
     #[tracing::instrument]
     fn from_str(s: &str) -> Result<Node, Self::Err> {
         Ok(Node {
+            hostname: None,
+            uuid: None,
             main_ip: s.into(),
             backplane_ip: s.into(),
+            user: None,
+            port: None,
+            tags: BTreeMap::new(),
+            timezone: None,
         })
     }
 }
 
+/// `--only` selector requiring a node to have a tag with a specific value
 #[derive(Debug)]
-struct ActiveJob<T>
-where
-    T: Read + Debug,
-{
-    incoming_lines: Option<T>,
-    ident: String,
+struct TagSelector {
+    key: String,
+    value: String,
 }
 
-impl<T> ActiveJob<T>
-where
-    T: Read + Debug,
-{
-    fn process_into(&mut self, send: Sender<String>) {
-        if ARGS.merge {
-            self.collate_into(send);
-        } else {
-            self.pretty_print(send);
+impl TagSelector {
+    fn matches(&self, node: &Node) -> bool {
+        node.tags.get(&self.key) == Some(&self.value)
+    }
+}
+
+impl std::str::FromStr for TagSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TagSelector, Self::Err> {
+        match s.find('=') {
+            Some(ind) => Ok(TagSelector {
+                key: s[..ind].into(),
+                value: s[ind + 1..].into(),
+            }),
+            None => Err(failure::format_err!("expected key=value, got {}", s)),
         }
     }
+}
 
-    fn pretty_print(&mut self, send: Sender<String>) {
-        send.send(format!(
-            "Running ({}) {}",
-            ARGS.command.join(" ").replace('\n', "; "),
-            self.ident
-        ))
-        .unwrap();
+/// Glob style pattern matched against a node's hostname, ips and uuid
+///
+/// Supports `*`, `?`, character classes like `[abc]` or `[!abc]`, and numeric ranges like
+/// `[01-08]` which keep the zero padding of their bounds.
+#[derive(Debug)]
+struct NodePattern(regex::Regex);
 
-        let read = BufReader::new(self.incoming_lines.take().unwrap());
+impl NodePattern {
+    fn matches(&self, node: &Node) -> bool {
+        let names = [
+            node.hostname.as_deref(),
+            node.uuid.as_deref(),
+            Some(&node.main_ip[..]),
+            Some(&node.backplane_ip[..]),
+        ];
 
-        for line in read.lines() {
-            let line = line.as_ref().unwrap();
-            let line = format!("        {}", line);
-            send.send(line).unwrap();
-        }
+        names.iter().flatten().any(|name| self.0.is_match(name))
     }
+}
 
-    #[tracing::instrument]
-    fn collate_into(&mut self, send: Sender<String>) {
-        let read = BufReader::new(self.incoming_lines.take().unwrap());
+impl std::str::FromStr for NodePattern {
+    type Err = Error;
 
-        self.pad_ident(15);
+    fn from_str(s: &str) -> Result<NodePattern, Self::Err> {
+        let mut re = String::from("^");
+        let mut rest = s;
 
-        let mut lastline: String = "0000-00-00 00:00:00.000000 fake line".into();
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '*' => re.push_str(".*"),
+                '?' => re.push('.'),
+                '[' => {
+                    let end = rest
+                        .find(']')
+                        .ok_or_else(|| failure::format_err!("unclosed [ in pattern {}", s))?;
+                    re.push_str(&glob_class_to_regex(&rest[..end])?);
+                    rest = &rest[end + 1..];
+                }
+                c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
 
-        for line in read.lines() {
-            let line = line.as_ref().unwrap();
+        re.push('$');
 
-            let line = if DATE.is_match(&line) {
-                let (part1, part2) = line.split_at(26);
-                lastline.clear();
-                lastline.push_str(line);
-                format!("{} {}{}", part1, self.ident, part2)
-            } else {
-                let (part1, _) = lastline.split_at(26);
-                format!("{} {} {}", part1, self.ident, line)
-            };
+        Ok(NodePattern(regex::Regex::new(&re)?))
+    }
+}
+
+/// Translate the inside of a glob `[...]` into a regex, expanding numeric ranges into an
+/// alternation of every number in the range
+fn glob_class_to_regex(class: &str) -> Result<String, Error> {
+    let range = class.find('-').and_then(|ind| {
+        let (low, high) = (&class[..ind], &class[ind + 1..]);
+        let width = if low.len() == high.len() { low.len() } else { 0 };
+        Some((low.parse::<u64>().ok()?, high.parse::<u64>().ok()?, width))
+    });
 
-            send.send(line).unwrap();
+    match range {
+        Some((low, high, _)) if low > high => {
+            Err(failure::format_err!("range [{}] is backwards", class))
+        }
+        Some((low, high, _)) if high - low >= MAX_PATTERN_RANGE => Err(failure::format_err!(
+            "range [{}] is over {} numbers",
+            class,
+            MAX_PATTERN_RANGE
+        )),
+        Some((low, high, width)) => {
+            let numbers: Vec<_> = (low..=high)
+                .map(|n| format!("{:0width$}", n, width = width))
+                .collect();
+            Ok(format!("(?:{})", numbers.join("|")))
+        }
+        None => {
+            let class = class.replace('\\', "\\\\");
+            match class.strip_prefix('!') {
+                Some(negated) => Ok(format!("[^{}]", negated)),
+                None => Ok(format!("[{}]", class)),
+            }
         }
     }
+}
 
-    #[tracing::instrument]
-    fn pad_ident(&mut self, size: usize) {
-        let padding_len = size - self.ident.len();
-        for _ in 0..padding_len {
-            self.ident.push(' ');
+/// Toml inventory file, a list of `[[node]]` tables
+#[derive(Debug, Deserialize)]
+struct Inventory {
+    #[serde(rename = "node", default)]
+    nodes: Vec<Node>,
+}
+
+/// Policy for deciding whether a run failed as a whole
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FailOn {
+    Any,
+    All,
+}
+
+impl std::str::FromStr for FailOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FailOn, Self::Err> {
+        match s {
+            "any" => Ok(FailOn::Any),
+            "all" => Ok(FailOn::All),
+            _ => Err(failure::format_err!("unknown failure policy: {}", s)),
         }
     }
 }
 
-#[tracing::instrument]
-fn print_completions(shell: Shell) {
-    Cli::clap().gen_completions_to("sca", shell, &mut io::stdout())
+/// How remote stderr is routed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StderrMode {
+    Merge,
+    Prefix,
+    Drop,
 }
 
-fn write_outputs_inorder(recvs: Vec<Receiver<String>>) {
-    for recv in recvs {
-        for line in recv.iter() {
-            println!("{}", line);
+impl std::str::FromStr for StderrMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StderrMode, Self::Err> {
+        match s {
+            "merge" => Ok(StderrMode::Merge),
+            "prefix" => Ok(StderrMode::Prefix),
+            "drop" => Ok(StderrMode::Drop),
+            _ => Err(failure::format_err!("unknown stderr mode: {}", s)),
         }
     }
 }
 
-fn merge_outputs(recvs: Vec<Receiver<String>>) {
-    let m = scale::merged_chan::MergedChannels::new(recvs);
+/// How bytes that aren't valid utf-8 are handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Lossy,
+    Raw,
+    Hex,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = Error;
 
-    for line in m {
-        println!("{}", line);
+    fn from_str(s: &str) -> Result<Encoding, Self::Err> {
+        match s {
+            "lossy" => Ok(Encoding::Lossy),
+            "raw" => Ok(Encoding::Raw),
+            "hex" => Ok(Encoding::Hex),
+            _ => Err(failure::format_err!("unknown encoding: {}", s)),
+        }
     }
 }
 
-fn spawn_jobs(nodes: &[Node]) {
-    crossbeam::scope(|scope| {
-        let mut recvs = vec![];
-        for node in nodes {
-            let (s, r) = crossbeam::channel::bounded(8096);
-            let ip = node.main_ip.clone();
-            let ident = node.backplane_ip.clone();
-            recvs.push(r);
-            let _ = scope.spawn(move |_| {
-                let args = &ARGS.command;
+impl Encoding {
+    /// Apply the encoding to a single line of remote output
+    fn decode(self, line: Vec<u8>) -> Vec<u8> {
+        match self {
+            Encoding::Raw => line,
+            Encoding::Lossy => match String::from_utf8_lossy(&line) {
+                Cow::Borrowed(_) => line,
+                Cow::Owned(decoded) => decoded.into_bytes(),
+            },
+            Encoding::Hex => {
+                let mut decoded = Vec::with_capacity(line.len());
+                let mut rest = &line[..];
 
-                let cwd = std::env::current_dir().unwrap();
+                loop {
+                    match std::str::from_utf8(rest) {
+                        Ok(valid) => {
+                            decoded.extend_from_slice(valid.as_bytes());
+                            break decoded;
+                        }
+                        Err(e) => {
+                            let (valid, invalid) = rest.split_at(e.valid_up_to());
+                            let invalid_len = e.error_len().unwrap_or(invalid.len());
+                            decoded.extend_from_slice(valid);
+                            for byte in &invalid[..invalid_len] {
+                                decoded.extend_from_slice(format!("\\x{:02x}", byte).as_bytes());
+                            }
+                            rest = &invalid[invalid_len..];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
 
-                let mut cmd = Command::new("ssh");
-                let _ = cmd
-                    .args(
-                        "-o UserKnownHostsFile=/dev/null -o StrictHostKeyChecking=no -q"
-                            .split_whitespace(),
-                    )
-                    .arg(ip)
-                    .arg("cd")
-                    .arg(cwd)
-                    .arg(";")
-                    .args(args)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped());
+/// How to parse the timestamps merged lines are sorted by
+#[derive(Debug, Clone, PartialEq)]
+enum TimestampFormat {
+    /// `2020-01-01 12:00:00.123456`, with a `T` or space separator and an optional offset
+    Iso8601,
+    /// `2020-01-01T12:00:00.123+02:00`
+    Rfc3339,
+    /// `Oct 18 02:57:01`, in the latest year that doesn't put it in the future
+    Syslog,
+    /// Seconds since the unix epoch, optionally with a fraction
+    Epoch,
+    /// Milliseconds since the unix epoch
+    EpochMillis,
+    /// Any strftime format, e.g. `%d/%b/%Y:%H:%M:%S %z`
+    Strftime(String),
+}
 
-                debug!(?cmd);
+impl std::str::FromStr for TimestampFormat {
+    type Err = Error;
 
-                let mut child = cmd.spawn().unwrap();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso8601" => Ok(TimestampFormat::Iso8601),
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "syslog" => Ok(TimestampFormat::Syslog),
+            "epoch" => Ok(TimestampFormat::Epoch),
+            "epoch-millis" => Ok(TimestampFormat::EpochMillis),
+            s if s.contains('%') => {
+                let _ = strftime_to_regex(s)?;
+                Ok(TimestampFormat::Strftime(s.into()))
+            }
+            _ => Err(failure::format_err!("unknown timestamp format: {}", s)),
+        }
+    }
+}
 
-                debug!(?child);
+impl TimestampFormat {
+    /// Regex matching a timestamp in this format
+    fn pattern(&self) -> String {
+        match self {
+            TimestampFormat::Iso8601 => {
+                r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?".into()
+            }
+            TimestampFormat::Rfc3339 => {
+                r"\d{4}-\d{2}-\d{2}[Tt ]\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:[Zz]|[+-]\d{2}:\d{2})".into()
+            }
+            TimestampFormat::Syslog => r"[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}".into(),
+            TimestampFormat::Epoch => r"\d{9,10}(?:\.\d+)?\b".into(),
+            TimestampFormat::EpochMillis => r"\d{12,13}\b".into(),
+            // checked when the format was parsed
+            TimestampFormat::Strftime(format) => strftime_to_regex(format).unwrap(),
+        }
+    }
 
-                let output = child.stdout.as_mut().unwrap();
+    /// Parse a timestamp matched by pattern, taking one without an offset to be in zone
+    fn parse(&self, ts: &str, zone: Zone) -> Option<DateTime<FixedOffset>> {
+        let utc = Utc.fix();
 
-                let mut job = ActiveJob {
-                    incoming_lines: Some(output),
-                    ident,
+        match self {
+            TimestampFormat::Iso8601 => {
+                let ts = ts
+                    .replacen('T', " ", 1)
+                    .replace(',', ".")
+                    .replace('Z', "+00:00");
+                DateTime::parse_from_str(&ts, "%Y-%m-%d %H:%M:%S%.f%z")
+                    .or_else(|_| {
+                        NaiveDateTime::parse_from_str(&ts, "%Y-%m-%d %H:%M:%S%.f")
+                            .map(|ts| zone.localize(&ts))
+                    })
+                    .ok()
+            }
+            TimestampFormat::Rfc3339 => {
+                DateTime::parse_from_rfc3339(&ts.replacen(' ', "T", 1)).ok()
+            }
+            TimestampFormat::Syslog => parse_without_year(ts, "%b %e %H:%M:%S", zone),
+            TimestampFormat::Epoch => {
+                let (secs, fraction) = match ts.find('.') {
+                    Some(ind) => (&ts[..ind], &ts[ind + 1..]),
+                    None => (ts, ""),
                 };
+                let nanos = format!("{:0<9.9}", fraction).parse().ok()?;
+                let ts = DateTime::from_timestamp(secs.parse().ok()?, nanos)?;
+                Some(ts.with_timezone(&utc))
+            }
+            TimestampFormat::EpochMillis => {
+                let millis: i64 = ts.parse().ok()?;
+                let nanos = millis.rem_euclid(1000) as u32 * 1_000_000;
+                let ts = DateTime::from_timestamp(millis.div_euclid(1000), nanos)?;
+                Some(ts.with_timezone(&utc))
+            }
+            TimestampFormat::Strftime(format) => DateTime::parse_from_str(ts, format)
+                .ok()
+                .or_else(|| {
+                    NaiveDateTime::parse_from_str(ts, format)
+                        .ok()
+                        .map(|ts| zone.localize(&ts))
+                })
+                .or_else(|| parse_without_year(ts, format, zone)),
+        }
+    }
+}
 
-                debug!(?job);
+/// Parse a timestamp in zone whose format has no year, in the latest year that doesn't put it in
+/// the future
+fn parse_without_year(ts: &str, format: &str, zone: Zone) -> Option<DateTime<FixedOffset>> {
+    let now = Utc::now().naive_utc();
+    let parse = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, ts), &format!("%Y {}", format)).ok()
+    };
 
-                job.process_into(s);
-            });
+    // a day of slack so a node whose clock is a little ahead doesn't get sent back a year, and
+    // enough years back for Feb 29 to find a leap year
+    let latest = now + chrono::Duration::days(1);
+    let parsed = (0..=8)
+        .filter_map(|back| parse(now.year() - back))
+        .find(|parsed| *parsed <= latest)?;
+
+    Some(zone.localize(&parsed))
+}
+
+/// Translate a strftime format into a regex matching the timestamps it produces
+fn strftime_to_regex(format: &str) -> Result<String, Error> {
+    let mut regex = String::new();
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            if c.is_whitespace() {
+                regex.push_str(r"\s+");
+            } else {
+                regex.push_str(&regex::escape(&c.to_string()));
+            }
+            continue;
         }
 
-        if ARGS.merge {
-            merge_outputs(recvs);
-        } else {
-            write_outputs_inorder(recvs);
+        let mut spec = String::new();
+        for c in &mut chars {
+            spec.push(c);
+            if c.is_ascii_alphabetic() || c == '%' {
+                break;
+            }
         }
-    })
-    .unwrap();
+
+        let pattern = match spec.trim_start_matches(&['-', '_', '0'][..]) {
+            "Y" => r"\d{4}",
+            "y" | "C" => r"\d{2}",
+            "m" | "d" | "e" | "H" | "k" | "I" | "l" | "M" | "S" => r"[ \d]?\d",
+            "j" => r"[ \d]{0,2}\d",
+            "b" | "h" | "a" => r"[A-Za-z]{3}",
+            "B" | "A" | "Z" => r"[A-Za-z]+",
+            "p" | "P" => r"[AaPp][Mm]",
+            "f" | "3f" | "6f" | "9f" | "s" => r"\d+",
+            ".f" => r"(?:\.\d+)?",
+            ".3f" | ".6f" | ".9f" => r"\.\d+",
+            "z" | ":z" => r"[+-]\d{2}:?\d{2}",
+            "F" => r"\d{4}-\d{2}-\d{2}",
+            "T" | "X" => r"\d{2}:\d{2}:\d{2}",
+            "R" => r"\d{2}:\d{2}",
+            "D" | "x" => r"\d{2}/\d{2}/\d{2}",
+            "n" | "t" => r"\s+",
+            "%" => "%",
+            _ => failure::bail!("unsupported strftime specifier: %{}", spec),
+        };
+        regex.push_str(pattern);
+    }
+
+    Ok(regex)
 }
 
-#[tracing::instrument]
-fn get_node_list_from_file(path: impl AsRef<Path> + Debug) -> Vec<Node> {
-    let nodes_lines = std::fs::read_to_string(path).unwrap();
+/// A timezone: a fixed offset like `+05:30`, an IANA name like `America/New_York`, or `local` for
+/// the zone sca itself is running in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+    Local,
+}
 
-    let mut nodes = vec![];
+impl std::str::FromStr for Zone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Zone::Local),
+            "UTC" | "utc" | "Z" => Ok(Zone::utc()),
+            s if s.starts_with('+') || s.starts_with('-') => {
+                let digits = s[1..].replace(':', "");
+                let (hours, minutes) = match (digits.len(), digits.parse::<i32>()) {
+                    (2, Ok(hours)) => (hours, 0),
+                    (4, Ok(hhmm)) => (hhmm / 100, hhmm % 100),
+                    _ => failure::bail!("invalid offset, expected +HH:MM: {}", s),
+                };
+                let secs = (hours * 60 + minutes) * 60;
+                let secs = if s.starts_with('-') { -secs } else { secs };
+                FixedOffset::east_opt(secs)
+                    .map(Zone::Fixed)
+                    .ok_or_else(|| failure::format_err!("offset out of range: {}", s))
+            }
+            s => s
+                .parse()
+                .map(Zone::Named)
+                .map_err(|_| failure::format_err!("unknown timezone: {}", s)),
+        }
+    }
+}
 
-    for line in nodes_lines.lines() {
-        let mut fields = line.split(' ');
-        let _uuid = fields.next().unwrap().to_string();
-        let backplane_ip = fields.next().unwrap().to_string();
-        let main_ip = fields.next().unwrap().to_string();
+impl<'de> Deserialize<'de> for Zone {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
-        nodes.push(Node {
-            main_ip,
-            backplane_ip,
-        });
+impl Zone {
+    fn utc() -> Self {
+        Zone::Fixed(Utc.fix())
     }
 
-    nodes
+    /// Place a timestamp read off a clock in this zone
+    ///
+    /// Ambiguous times from a daylight saving change take the earlier reading, and the times
+    /// skipped over by one take the offset from before it.
+    fn localize(self, ts: &NaiveDateTime) -> DateTime<FixedOffset> {
+        let offset = match self {
+            Zone::Fixed(offset) => offset,
+            Zone::Named(tz) => match tz.offset_from_local_datetime(ts).earliest() {
+                Some(offset) => offset.fix(),
+                None => tz.offset_from_utc_datetime(ts).fix(),
+            },
+            Zone::Local => match Local.offset_from_local_datetime(ts).earliest() {
+                Some(offset) => offset,
+                None => Local.offset_from_utc_datetime(ts),
+            },
+        };
+
+        DateTime::from_naive_utc_and_offset(*ts - offset, offset)
+    }
+
+    /// The same instant as ts with the offset this zone had at the time
+    fn convert(self, ts: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let offset = match self {
+            Zone::Fixed(offset) => offset,
+            Zone::Named(tz) => tz.offset_from_utc_datetime(&ts.naive_utc()).fix(),
+            Zone::Local => Local.offset_from_utc_datetime(&ts.naive_utc()),
+        };
+
+        ts.with_timezone(&offset)
+    }
+
+    /// Render ts as it would read on a clock in this zone
+    fn render(self, ts: DateTime<FixedOffset>) -> String {
+        self.convert(ts)
+            .format("%Y-%m-%d %H:%M:%S%.6f%:z")
+            .to_string()
+    }
 }
 
-#[tracing::instrument]
-fn get_node_list() -> Vec<Node> {
-    get_node_list_from_file(panic!("redacted"))
+/// Find and parse the timestamp in line according to `--timestamp-format` and
+/// `--timestamp-regex`, returning where it is along with its value
+///
+/// Timestamps without an offset are taken to be in zone.
+fn find_timestamp(line: &[u8], zone: Zone) -> Option<(Range<usize>, DateTime<FixedOffset>)> {
+    let captures = TIMESTAMP.captures(line)?;
+    let found = captures.get(1).or_else(|| captures.get(0))?;
+    let ts = ARGS
+        .timestamp_format
+        .parse(std::str::from_utf8(found.as_bytes()).ok()?, zone)?;
+
+    Some((found.range(), ts))
 }
 
-#[tracing::instrument]
-fn run() -> Result<(), Error> {
-    let args = ARGS.deref();
-    debug!(?args);
+/// A line of output on its way from a job to stdout
+///
+/// When merging this is a whole record, a timestamped line along with the lines continuing it, so
+/// the record can't be split up by lines from other nodes.
+#[derive(Debug, Clone)]
+struct Line {
+    /// When the line was logged, only known when merging
+    ts: Option<DateTime<FixedOffset>>,
+    text: Vec<u8>,
+}
 
-    let mut nodes = get_node_list();
-    nodes.extend_from_slice(&args.nodes);
+impl From<Vec<u8>> for Line {
+    fn from(text: Vec<u8>) -> Self {
+        Line { ts: None, text }
+    }
+}
+
+/// How much of a node's output `--reorder-window` holds back for sorting
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReorderWindow {
+    Time(Duration),
+    Lines(usize),
+}
 
-    spawn_jobs(&nodes);
+impl std::str::FromStr for ReorderWindow {
+    type Err = Error;
 
-    Ok(())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(lines) => Ok(ReorderWindow::Lines(lines)),
+            Err(_) => humantime::parse_duration(s)
+                .map(ReorderWindow::Time)
+                .map_err(|e| failure::format_err!("expected a duration or a line count: {}", e)),
+        }
+    }
 }
 
-#[tracing::instrument]
-fn main() {
-    scale::init_script("info");
+/// Sorts a held line by timestamp, then by arrival so lines with the same timestamp keep their
+/// order
+type ReorderKey = (Option<DateTime<FixedOffset>>, u64);
 
-    if let Some(shell) = ARGS.shell {
-        print_completions(shell);
-    } else {
-        scale::handle_script_result(run());
+/// Holds back a node's lines within the reorder window so they come out sorted by timestamp
+#[derive(Debug)]
+struct ReorderBuffer {
+    window: ReorderWindow,
+    /// Lines along with when each arrived
+    pending: BTreeMap<ReorderKey, (Instant, Line)>,
+    arrived: u64,
+    newest: Option<DateTime<FixedOffset>>,
+}
+
+impl ReorderBuffer {
+    fn new(window: ReorderWindow) -> Self {
+        ReorderBuffer {
+            window,
+            pending: BTreeMap::new(),
+            arrived: 0,
+            newest: None,
+        }
+    }
+
+    /// Add a line, returning the lines that have now left the window in sorted order
+    fn push(&mut self, line: Line) -> Vec<Line> {
+        self.newest = self.newest.max(line.ts);
+        let _ = self
+            .pending
+            .insert((line.ts, self.arrived), (Instant::now(), line));
+        self.arrived += 1;
+
+        let mut ready = vec![];
+        while let Some((&(ts, _), _)) = self.pending.iter().next() {
+            let expired = match (self.window, ts, self.newest) {
+                (ReorderWindow::Lines(lines), _, _) => self.pending.len() > lines,
+                (ReorderWindow::Time(window), Some(ts), Some(newest)) => {
+                    (newest - ts).to_std().is_ok_and(|held| held >= window)
+                }
+                // nothing to sort an untimestamped line by, so there's no point holding it
+                (ReorderWindow::Time(_), _, _) => true,
+            };
+
+            if !expired {
+                break;
+            }
+
+            ready.extend(self.pending.pop_first().map(|(_, (_, line))| line));
+        }
+
+        ready
+    }
+
+    /// Lines to release now that the node has gone quiet, in sorted order
+    ///
+    /// Without more lines arriving nothing would leave the window, so a time window releases
+    /// every line held for longer than the window by the clock, along with any sorting before
+    /// them. A lines window has nothing left to sort what it holds against and releases it all.
+    fn idle(&mut self) -> Vec<Line> {
+        let now = Instant::now();
+        let release = match self.window {
+            ReorderWindow::Lines(_) => self.pending.len(),
+            ReorderWindow::Time(window) => self
+                .pending
+                .values()
+                .rposition(|(arrived, _)| now - *arrived >= window)
+                .map_or(0, |ind| ind + 1),
+        };
+
+        (0..release)
+            .flat_map(|_| self.pending.pop_first())
+            .map(|(_, (_, line))| line)
+            .collect()
+    }
+
+    /// Everything still held back, in sorted order
+    fn finish(self) -> impl Iterator<Item = Line> {
+        self.pending.into_values().map(|(_, line)| line)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `--continuation`: how lines without a timestamp are shown under the line they continue
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Continuation {
+    Restamp,
+    Indent,
+}
 
-    #[test]
-    fn grab_nodes() {
-        scale::init_script("info");
+impl std::str::FromStr for Continuation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restamp" => Ok(Continuation::Restamp),
+            "indent" => Ok(Continuation::Indent),
+            _ => Err(failure::format_err!("unknown continuation style: {}", s)),
+        }
+    }
+}
 
-        let nodes = get_node_list_from_file(panic!("redacted"));
+/// `--format` template laying out output lines, e.g. `{ts} {node:<20} {line}`
+#[derive(Debug, PartialEq)]
+struct Template(Vec<Piece>);
 
-        info!(?nodes);
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Literal(String),
+    Field {
+        field: Field,
+        align: Align,
+        width: Option<usize>,
+    },
+}
 
-        assert_eq!(3, nodes.len());
+/// A value `--format` can put in an output line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Ts,
+    Node,
+    MainIp,
+    BackplaneIp,
+    Hostname,
+    Stream,
+    LineNo,
+    Line,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+impl std::str::FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+
+        while let Some(c) = rest.chars().next() {
+            if let Some(tail) = rest.strip_prefix("{{") {
+                literal.push('{');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("}}") {
+                literal.push('}');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('{') {
+                let end = tail
+                    .find('}')
+                    .ok_or_else(|| failure::format_err!("unclosed {{ in format: {}", s))?;
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(tail[..end].parse()?);
+                rest = &tail[end + 1..];
+            } else if c == '}' {
+                failure::bail!("unmatched }} in format: {}", s);
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+
+        Ok(Template(pieces))
+    }
+}
+
+impl std::str::FromStr for Piece {
+    type Err = Error;
+
+    /// Parse the inside of a `{field:<width}` placeholder
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = match s.find(':') {
+            Some(ind) => (&s[..ind], &s[ind + 1..]),
+            None => (s, ""),
+        };
+
+        let (align, width) = match spec.chars().next() {
+            Some('<') => (Align::Left, &spec[1..]),
+            Some('>') => (Align::Right, &spec[1..]),
+            Some('^') => (Align::Center, &spec[1..]),
+            _ => (Align::Left, spec),
+        };
+
+        let width = match width {
+            "" => None,
+            width => Some(
+                width
+                    .parse()
+                    .map_err(|_| failure::format_err!("bad width in format field: {}", s))?,
+            ),
+        };
+
+        Ok(Piece::Field {
+            field: name.parse()?,
+            align,
+            width,
+        })
+    }
+}
+
+impl std::str::FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ts" => Ok(Field::Ts),
+            "node" => Ok(Field::Node),
+            "main_ip" => Ok(Field::MainIp),
+            "backplane_ip" => Ok(Field::BackplaneIp),
+            "hostname" => Ok(Field::Hostname),
+            "stream" => Ok(Field::Stream),
+            "lineno" => Ok(Field::LineNo),
+            "line" => Ok(Field::Line),
+            _ => Err(failure::format_err!("unknown format field: {}", s)),
+        }
+    }
+}
+
+/// The values a Template lays out for one line
+#[derive(Debug)]
+struct Fields<'a> {
+    ts: &'a [u8],
+    node: &'a Node,
+    ident: &'a str,
+    stream: Stream,
+    lineno: u64,
+    line: &'a [u8],
+}
+
+impl Fields<'_> {
+    fn get(&self, field: Field) -> Cow<'_, [u8]> {
+        match field {
+            Field::Ts => self.ts.into(),
+            Field::Node => self.ident.as_bytes().into(),
+            Field::MainIp => self.node.main_ip.as_bytes().into(),
+            Field::BackplaneIp => self.node.backplane_ip.as_bytes().into(),
+            Field::Hostname => self
+                .node
+                .hostname
+                .as_deref()
+                .unwrap_or("")
+                .as_bytes()
+                .into(),
+            Field::Stream => self.stream.to_string().into_bytes().into(),
+            Field::LineNo => self.lineno.to_string().into_bytes().into(),
+            Field::Line => self.line.into(),
+        }
+    }
+}
+
+impl Template {
+    /// Lay out a line. With blank everything but {line} is spaces instead, so a continuation
+    /// line can be indented under the line it continues
+    fn render(&self, fields: &Fields<'_>, blank: bool) -> Vec<u8> {
+        let mut out = vec![];
+
+        for piece in &self.0 {
+            let (value, align, width, is_line) = match piece {
+                Piece::Literal(literal) => (literal.as_bytes().into(), Align::Left, None, false),
+                Piece::Field {
+                    field,
+                    align,
+                    width,
+                } => (fields.get(*field), *align, *width, *field == Field::Line),
+            };
+
+            let value_width = String::from_utf8_lossy(&value).width();
+            let padding = width.map_or(0, |width| width.saturating_sub(value_width));
+
+            if blank && !is_line {
+                out.resize(out.len() + value_width + padding, b' ');
+                continue;
+            }
+
+            let before = match align {
+                Align::Left => 0,
+                Align::Right => padding,
+                Align::Center => padding / 2,
+            };
+            out.resize(out.len() + before, b' ');
+            out.extend_from_slice(&value);
+            out.resize(out.len() + padding - before, b' ');
+        }
+
+        out
+    }
+}
+
+/// `--output`: how lines are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputMode {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputMode::Text),
+            "json" => Ok(OutputMode::Json),
+            _ => Err(failure::format_err!("unknown output mode: {}", s)),
+        }
+    }
+}
+
+/// A line of `--output json`
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    node: &'a str,
+    hostname: Option<&'a str>,
+    uuid: Option<&'a str>,
+    main_ip: &'a str,
+    stream: Stream,
+    /// Only known when merging
+    ts: Option<String>,
+    lineno: u64,
+    text: Cow<'a, str>,
+}
+
+/// The closing object of `--output json` for a single node
+#[derive(Debug, Serialize)]
+struct JsonSummary<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    node: &'a str,
+    success: bool,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    errors: Vec<String>,
+    started: Option<String>,
+    elapsed_secs: Option<f64>,
+    skew_ms: Option<f64>,
+    unsorted: usize,
+}
+
+/// Mark each object of a --output json record as having arrived too late to be merged in order
+fn mark_late_json(text: &[u8]) -> Vec<u8> {
+    let objects: Vec<_> = text
+        .split(|&b| b == b'\n')
+        .map(|object| [&b"{\"late\":true,"[..], &object[1..]].concat())
+        .collect();
+
+    objects.join(&b'\n')
+}
+
+/// Which output stream of the remote command a job is reading
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Everything that can go wrong while running the command on a single node
+#[derive(Debug, Fail)]
+enum JobError {
+    #[fail(display = "failed to spawn ssh: {}", _0)]
+    Spawn(#[cause] io::Error),
+    #[fail(display = "failed to read {}: {}", _0, _1)]
+    Read(Stream, #[cause] io::Error),
+    #[fail(display = "failed to wait for ssh: {}", _0)]
+    Wait(#[cause] io::Error),
+    #[fail(display = "output channel closed before {} was finished", _0)]
+    ChannelClosed(Stream),
+    #[fail(display = "killed after running longer than {:?}", _0)]
+    Timeout(Duration),
+    #[fail(display = "skipped after the command failed on an earlier node")]
+    Skipped,
+    #[fail(display = "failed to save output: {}", _0)]
+    Archive(#[cause] io::Error),
+}
+
+/// Outcome of running the command on a single node
+#[derive(Debug)]
+struct JobReport {
+    ident: String,
+    /// None if ssh never got as far as exiting, or if --follow was stopped
+    status: Option<ExitStatus>,
+    errors: Vec<JobError>,
+    /// Clock skew the node's timestamps were corrected by
+    skew: Option<Skew>,
+    /// Lines merged with an earlier timestamp than a line before them
+    unsorted: usize,
+    /// When the node was started on, None if it was skipped
+    started: Option<DateTime<Utc>>,
+    /// How long the node took, or was followed for
+    elapsed: Option<Duration>,
+}
+
+impl JobReport {
+    fn new(node: &Node) -> Self {
+        JobReport {
+            ident: node.backplane_ip.clone(),
+            status: None,
+            errors: vec![],
+            skew: None,
+            unsorted: 0,
+            started: None,
+            elapsed: None,
+        }
+    }
+
+    fn skipped(node: &Node) -> Self {
+        JobReport {
+            errors: vec![JobError::Skipped],
+            ..JobReport::new(node)
+        }
+    }
+
+    fn success(&self) -> bool {
+        let stopped = self.status.is_none() && STOPPING.load(Ordering::SeqCst);
+
+        self.errors.is_empty() && (stopped || self.status.is_some_and(|status| status.success()))
+    }
+
+    fn to_json(&self) -> JsonSummary<'_> {
+        JsonSummary {
+            kind: "summary",
+            node: &self.ident,
+            success: self.success(),
+            exit_code: self.status.and_then(|status| status.code()),
+            signal: self.status.and_then(|status| status.signal()),
+            errors: self.errors.iter().map(ToString::to_string).collect(),
+            started: self.started.map(|started| started.to_rfc3339()),
+            elapsed_secs: self.elapsed.map(|elapsed| elapsed.as_secs_f64()),
+            skew_ms: self
+                .skew
+                .and_then(|skew| skew.offset.num_microseconds())
+                .map(|us| us as f64 / 1000.0),
+            unsorted: self.unsorted,
+        }
+    }
+}
+
+impl fmt::Display for JobReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.ident)?;
+
+        match self.status {
+            Some(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => write!(f, "exit {}", code)?,
+                (None, Some(signal)) => write!(f, "killed by signal {}", signal)?,
+                (None, None) => write!(f, "{}", status)?,
+            },
+            None if self.errors.is_empty() && STOPPING.load(Ordering::SeqCst) => {
+                write!(f, "stopped")?
+            }
+            None => write!(f, "did not run")?,
+        }
+
+        if let Some(skew) = &self.skew {
+            write!(f, ", {}", skew)?;
+        }
+
+        if self.unsorted > 0 {
+            write!(f, ", {} lines out of order", self.unsorted)?;
+        }
+
+        for error in &self.errors {
+            write!(f, ", {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ActiveJob<T>
+where
+    T: Read + Debug,
+{
+    incoming_lines: Option<T>,
+    ident: String,
+    node: Node,
+    stream: Stream,
+    /// Zone the node logs in
+    zone: Zone,
+    /// How far the node's clock is ahead of ours
+    skew: chrono::Duration,
+    /// Lines sent with an earlier timestamp than a line before them
+    unsorted: usize,
+    /// Width of the ident column when merging
+    ident_width: usize,
+    /// Lines read from this stream of the node so far
+    lines: Arc<AtomicU64>,
+}
+
+impl<T> ActiveJob<T>
+where
+    T: Read + Debug + Send,
+{
+    fn process_into(&mut self, send: Sender<Line>) -> Result<(), JobError> {
+        if ARGS.merging() {
+            self.collate_into(send)
+        } else if ARGS.output == OutputMode::Json {
+            self.json_into(send)
+        } else if ARGS.stream {
+            self.prefix_into(send)
+        } else {
+            self.pretty_print(send)
+        }
+    }
+
+    /// Send each line as a line of --output json
+    fn json_into(&mut self, send: Sender<Line>) -> Result<(), JobError> {
+        let read = self.incoming_lines.take().unwrap();
+        let stream = self.stream;
+
+        for_each_line(read, stream, ARGS.encoding, |line| {
+            send.send(self.json_line(None, &line).into())
+                .map_err(|_| JobError::ChannelClosed(stream))
+        })
+    }
+
+    /// Send each line prefixed with the node ident so it can be told apart once interleaved
+    fn prefix_into(&mut self, send: Sender<Line>) -> Result<(), JobError> {
+        let read = self.incoming_lines.take().unwrap();
+        let stream = self.stream;
+
+        let prefix = match stream {
+            Stream::Stdout => format!("{}: ", self.ident),
+            Stream::Stderr => format!("{}: {} ", self.ident, STDERR_MARKER),
+        };
+
+        for_each_line(read, stream, ARGS.encoding, |line| {
+            let line = match &ARGS.format {
+                Some(format) => self.format_line(format, b"", &line, false),
+                None => [prefix.as_bytes(), &line].concat(),
+            };
+            send.send(line.into())
+                .map_err(|_| JobError::ChannelClosed(stream))
+        })
+    }
+
+    fn pretty_print(&mut self, send: Sender<Line>) -> Result<(), JobError> {
+        let read = self.incoming_lines.take().unwrap();
+        let stream = self.stream;
+
+        if stream == Stream::Stdout {
+            let banner = format!(
+                "Running ({}) {}",
+                ARGS.command.join(" ").replace('\n', "; "),
+                self.ident
+            );
+            send.send(banner.into_bytes().into())
+                .map_err(|_| JobError::ChannelClosed(stream))?;
+        }
+
+        let indent = match stream {
+            Stream::Stdout => "        ".to_string(),
+            Stream::Stderr => format!("        {} ", STDERR_MARKER),
+        };
+
+        for_each_line(read, stream, ARGS.encoding, |line| {
+            let line = match &ARGS.format {
+                Some(format) => self.format_line(format, b"", &line, false),
+                None => [indent.as_bytes(), &line].concat(),
+            };
+            send.send(line.into())
+                .map_err(|_| JobError::ChannelClosed(stream))
+        })
+    }
+
+    /// Read everything without passing it on, so it still reaches --output-dir
+    fn discard(&mut self) -> Result<(), JobError> {
+        let mut read = self.incoming_lines.take().unwrap();
+
+        io::copy(&mut read, &mut io::sink())
+            .map(|_| ())
+            .map_err(|e| JobError::Read(self.stream, e))
+    }
+
+    /// Write each line straight to local stderr prefixed with the node ident
+    fn forward_to_stderr(&mut self) -> Result<(), JobError> {
+        let read = self.incoming_lines.take().unwrap();
+        let prefix = format!("{}: ", self.ident);
+
+        for_each_line(read, self.stream, ARGS.encoding, |line| {
+            let mut line = match (ARGS.output, &ARGS.format) {
+                (OutputMode::Json, _) => self.json_line(None, &line),
+                (_, Some(format)) => self.format_line(format, b"", &line, false),
+                (_, None) => [prefix.as_bytes(), &line].concat(),
+            };
+            line.push(b'\n');
+            // a failure to write our own stderr has nowhere better to be reported
+            let _ = io::stderr().write_all(&line);
+            Ok(())
+        })
+    }
+
+    #[tracing::instrument]
+    fn collate_into(&mut self, send: Sender<Line>) -> Result<(), JobError> {
+        let read = self.incoming_lines.take().unwrap();
+        let stream = self.stream;
+
+        self.pad_ident(self.ident_width);
+
+        let ident = match stream {
+            Stream::Stdout => self.ident.clone(),
+            Stream::Stderr => format!("{} {}", self.ident, STDERR_MARKER),
+        };
+
+        let (zone, skew) = (self.zone, self.skew);
+
+        // lines before the first timestamp sort before everything else
+        let mut last_stamp: Vec<u8> = b"0000-00-00 00:00:00.000000".to_vec();
+        let mut last_ts = None;
+
+        let mut reorder = ARGS.reorder_window.map(ReorderBuffer::new);
+        let mut last_sent = None;
+        let mut unsorted = 0;
+
+        let mut record: Option<Line> = None;
+
+        let mut send = |line: Line| {
+            if line.ts < last_sent {
+                unsorted += 1;
+                if ARGS.report_unsorted {
+                    warn!(
+                        message = "line out of order",
+                        ident = %ident.trim_end(),
+                        line = %String::from_utf8_lossy(&line.text),
+                    );
+                }
+            } else {
+                last_sent = line.ts;
+            }
+
+            send.send(line).map_err(|_| JobError::ChannelClosed(stream))
+        };
+
+        // Send a finished record on through the reorder window if there is one. None is for when
+        // the node has gone quiet, to send on what the window has held long enough
+        let mut emit = |line: Option<Line>| {
+            let ready = match (&mut reorder, line) {
+                (Some(reorder), Some(line)) => reorder.push(line),
+                (Some(reorder), None) => reorder.idle(),
+                (None, line) => line.into_iter().collect(),
+            };
+            ready.into_iter().try_for_each(&mut send)
+        };
+
+        let mut handle = |line: Option<Vec<u8>>| {
+            let line = match line {
+                Some(line) => line,
+                // The node has gone quiet, so send the record instead of holding it back from the
+                // merge past its lateness window. Anything that turns up to continue it after
+                // all goes out as a record of its own, which other nodes' lines with the same
+                // timestamp may be merged in front of
+                None => {
+                    if let Some(record) = record.take() {
+                        emit(Some(record))?;
+                    }
+                    return emit(None);
+                }
+            };
+
+            match find_timestamp(&line, zone) {
+                Some((found, ts)) => {
+                    let ts = ts - skew;
+                    // a corrected timestamp no longer says what the line did
+                    let render = match ARGS.output_timezone {
+                        Some(output) => Some(output),
+                        None if ARGS.correct_skew => Some(Zone::Fixed(*ts.offset())),
+                        None => None,
+                    };
+                    let stamp = match render {
+                        Some(zone) => zone.render(ts).into_bytes(),
+                        None => line[found.clone()].to_vec(),
+                    };
+                    let (part1, part2) = (&line[..found.start], &line[found.end..]);
+                    let text = match (ARGS.output, &ARGS.format) {
+                        (OutputMode::Json, _) => self.json_line(Some(ts), &line),
+                        (_, Some(format)) => {
+                            let rest = [part1, part2].concat();
+                            let start = rest.iter().position(|b| !b.is_ascii_whitespace());
+                            let rest = &rest[start.unwrap_or(rest.len())..];
+                            self.format_line(format, &stamp, rest, false)
+                        }
+                        (_, None) => [part1, &stamp, b" ", ident.as_bytes(), part2].concat(),
+                    };
+                    last_stamp = stamp;
+                    last_ts = Some(ts);
+
+                    let finished = record.replace(Line { ts: last_ts, text });
+                    finished.map_or(Ok(()), |finished| emit(Some(finished)))
+                }
+                None => {
+                    let text = match (&ARGS.format, ARGS.continuation) {
+                        _ if ARGS.output == OutputMode::Json => self.json_line(last_ts, &line),
+                        (Some(format), continuation) => {
+                            let blank = continuation == Continuation::Indent;
+                            self.format_line(format, &last_stamp, &line, blank)
+                        }
+                        (None, Continuation::Restamp) => {
+                            [&last_stamp[..], b" ", ident.as_bytes(), b" ", &line].concat()
+                        }
+                        (None, Continuation::Indent) => {
+                            let indent = vec![b' '; last_stamp.len() + 1 + ident.width()];
+                            [&indent[..], b" ", &line].concat()
+                        }
+                    };
+
+                    match &mut record {
+                        Some(record) => {
+                            record.text.push(b'\n');
+                            record.text.extend(text);
+                        }
+                        None => record = Some(Line { ts: last_ts, text }),
+                    }
+
+                    Ok(())
+                }
+            }
+        };
+
+        // Without a lateness window the merge waits for every record however long it takes, so
+        // a record is only complete once the next one starts or the output ends. With one, half
+        // of it is left for the record to reach the merge before it gives up on the node
+        let result = match ARGS.merge_lateness() {
+            Some(lateness) => {
+                let idle = (lateness / 2).max(MIN_IDLE_INTERVAL);
+                for_each_line_or_idle(read, stream, ARGS.encoding, idle, &mut handle)
+            }
+            None => for_each_line(read, stream, ARGS.encoding, |line| handle(Some(line))),
+        };
+
+        let result = result
+            .and_then(|()| record.take().map_or(Ok(()), |record| emit(Some(record))))
+            .and_then(|()| {
+                reorder
+                    .into_iter()
+                    .flat_map(ReorderBuffer::finish)
+                    .try_for_each(&mut send)
+            });
+
+        self.unsorted += unsorted;
+
+        result
+    }
+
+    /// Lay out the next line read from the node with --format
+    fn format_line(&self, format: &Template, ts: &[u8], line: &[u8], blank: bool) -> Vec<u8> {
+        let fields = Fields {
+            ts,
+            node: &self.node,
+            ident: &self.ident,
+            stream: self.stream,
+            lineno: self.lines.fetch_add(1, Ordering::SeqCst) + 1,
+            line,
+        };
+
+        format.render(&fields, blank)
+    }
+
+    /// The next line read from the node as a line of --output json
+    fn json_line(&self, ts: Option<DateTime<FixedOffset>>, line: &[u8]) -> Vec<u8> {
+        let ts = ts.map(|ts| match ARGS.output_timezone {
+            Some(zone) => zone.convert(ts).to_rfc3339(),
+            None => ts.to_rfc3339(),
+        });
+
+        let json = JsonLine {
+            kind: "line",
+            node: &self.node.backplane_ip,
+            hostname: self.node.hostname.as_deref(),
+            uuid: self.node.uuid.as_deref(),
+            main_ip: &self.node.main_ip,
+            stream: self.stream,
+            ts,
+            lineno: self.lines.fetch_add(1, Ordering::SeqCst) + 1,
+            text: String::from_utf8_lossy(line),
+        };
+
+        // nothing in a JsonLine can fail to serialize
+        serde_json::to_vec(&json).unwrap()
+    }
+
+    #[tracing::instrument]
+    fn pad_ident(&mut self, width: usize) {
+        self.ident = fit_to_width(&self.ident, width);
+    }
+}
+
+/// Pad s with spaces or cut it short so it takes up exactly width terminal columns
+fn fit_to_width(s: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut used = 0;
+
+    for c in s.chars() {
+        let c_width = c.width().unwrap_or(0);
+        if used + c_width > width {
+            break;
+        }
+        fitted.push(c);
+        used += c_width;
+    }
+
+    fitted.push_str(&" ".repeat(width - used));
+    fitted
+}
+
+/// Feed each line of read to handle, decoded with encoding, stopping at the first error
+///
+/// Whatever is left of the stream after an error is discarded rather than left unread so the
+/// remote command doesn't block forever on a full pipe. A followed file never ends, so with
+/// --follow the rest is dropped instead.
+fn for_each_line<R: Read>(
+    read: R,
+    stream: Stream,
+    encoding: Encoding,
+    mut handle: impl FnMut(Vec<u8>) -> Result<(), JobError>,
+) -> Result<(), JobError> {
+    let mut read = BufReader::new(read);
+
+    let result = loop {
+        let mut line = vec![];
+        match read.read_until(b'\n', &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    let _ = line.pop();
+                    if line.last() == Some(&b'\r') {
+                        let _ = line.pop();
+                    }
+                }
+
+                if let Err(e) = handle(encoding.decode(line)) {
+                    break Err(e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(JobError::Read(stream, e)),
+        }
+    };
+
+    if result.is_err() && ARGS.follow.is_none() {
+        let _ = io::copy(&mut read, &mut io::sink());
+    }
+
+    result
+}
+
+/// Like for_each_line, but also calls handle with None every time idle passes without a new line
+///
+/// The lines are read on another thread so the wait for the next one can time out.
+fn for_each_line_or_idle<R: Read + Send>(
+    read: R,
+    stream: Stream,
+    encoding: Encoding,
+    idle: Duration,
+    mut handle: impl FnMut(Option<Vec<u8>>) -> Result<(), JobError>,
+) -> Result<(), JobError> {
+    crossbeam::scope(|scope| {
+        let (send, lines) = crossbeam::channel::bounded(64);
+
+        // once handle fails the channel is closed, which stops this reading and discards the rest
+        let reader = scope.spawn(move |_| {
+            for_each_line(read, stream, encoding, |line| {
+                send.send(line).map_err(|_| JobError::ChannelClosed(stream))
+            })
+        });
+
+        let handled = loop {
+            let result = match lines.recv_timeout(idle) {
+                Ok(line) => handle(Some(line)),
+                Err(RecvTimeoutError::Timeout) => handle(None),
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            };
+
+            if result.is_err() {
+                break result;
+            }
+        };
+
+        drop(lines);
+        let read = reader.join().unwrap();
+
+        handled.and(read)
+    })
+    .unwrap()
+}
+
+#[tracing::instrument]
+fn print_completions(shell: Shell) {
+    Cli::clap().gen_completions_to("sca", shell, &mut io::stdout())
+}
+
+/// Write a single output line to out
+fn write_line(out: &mut impl Write, line: &[u8]) -> io::Result<()> {
+    out.write_all(line)?;
+    out.write_all(b"\n")
+}
+
+fn write_outputs_inorder(recvs: Vec<Receiver<Line>>) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for recv in recvs {
+        for line in recv.iter() {
+            write_line(&mut out, &line.text)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print lines from whichever channel has one ready, without waiting on any single node
+fn stream_outputs(recvs: Vec<Receiver<Line>>) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    let mut select = Select::new();
+    for recv in &recvs {
+        let _ = select.recv(recv);
+    }
+
+    let mut open = recvs.len();
+    while open > 0 {
+        let oper = select.select();
+        let id = oper.index();
+        match oper.recv(&recvs[id]) {
+            Ok(line) => write_line(&mut out, &line.text)?,
+            Err(_) => {
+                select.remove(id);
+                open -= 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_outputs(recvs: Vec<Receiver<Line>>) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    let m = MergedChannels::by_key(recvs, |line: &Line| line.ts);
+
+    let lateness = match ARGS.merge_lateness() {
+        Some(lateness) => lateness,
+        None => {
+            for line in m {
+                write_line(&mut out, &line.text)?;
+            }
+
+            return Ok(());
+        }
+    };
+
+    for Followed { item: line, late } in m.follow(lateness) {
+        if late && ARGS.flag_late {
+            if ARGS.output == OutputMode::Json {
+                write_line(&mut out, &mark_late_json(&line.text))?;
+                continue;
+            }
+
+            out.write_all(LATE_MARKER.as_bytes())?;
+            out.write_all(b" ")?;
+        }
+        write_line(&mut out, &line.text)?;
+    }
+
+    Ok(())
+}
+
+/// Run the command on every node and write their output to stdout
+///
+/// An error writing to stdout stops the output early, the jobs are still waited on so the
+/// reports are complete.
+fn spawn_jobs(
+    nodes: &[Node],
+    skews: &[Option<Skew>],
+    cwd: &Path,
+) -> (Vec<JobReport>, io::Result<()>) {
+    let parallel = match (ARGS.serial, ARGS.parallel) {
+        (true, _) => 1,
+        (false, Some(parallel)) => parallel.max(1),
+        (false, None) => nodes.len(),
+    };
+
+    // The merge reads from every channel before it yields anything, so when some nodes have to
+    // wait for a free slot, or a node's stderr is a channel of its own that may stay empty until
+    // the command exits, the running ones must never block on a full channel
+    let merged_stderr = ARGS.stderr == StderrMode::Merge;
+    let capacity = if ARGS.merging() && (parallel < nodes.len() || merged_stderr) {
+        None
+    } else {
+        Some(8096)
+    };
+
+    let ident_width = ARGS.ident_width.unwrap_or_else(|| {
+        nodes
+            .iter()
+            .map(|node| node.backplane_ip.width())
+            .max()
+            .unwrap_or(0)
+    });
+
+    // set once a node fails with --stop-on-failure so no more nodes are started
+    let stop = AtomicBool::new(false);
+
+    crossbeam::scope(|scope| {
+        let (queue, jobs) = crossbeam::channel::unbounded();
+        let mut recvs = vec![];
+        for (ind, node) in nodes.iter().enumerate() {
+            let (s, r) = match capacity {
+                Some(capacity) => crossbeam::channel::bounded(capacity),
+                None => crossbeam::channel::unbounded(),
+            };
+            recvs.push(r);
+
+            // stderr gets an unbounded channel because in grouped mode it is only drained after
+            // stdout closes, and a full stderr channel would stall the remote command forever
+            let err_send = if merged_stderr {
+                let (s, r) = crossbeam::channel::unbounded();
+                recvs.push(r);
+                Some(s)
+            } else {
+                None
+            };
+
+            queue.send((ind, node, s, err_send)).unwrap();
+        }
+        drop(queue);
+
+        // Workers take nodes off the queue in inventory order, so the grouped output which is
+        // drained in the same order is always waiting on a node that has already started
+        let mut handles = vec![];
+        for _ in 0..parallel.min(nodes.len()) {
+            let jobs = jobs.clone();
+            let stop = &stop;
+            handles.push(scope.spawn(move |scope| {
+                jobs.iter()
+                    .map(|(ind, node, s, err_send)| {
+                        if stop.load(Ordering::SeqCst) {
+                            return (ind, JobReport::skipped(node));
+                        }
+
+                        let archive = match &ARGS.output_dir {
+                            Some(dir) => match Archive::create(dir, node) {
+                                Ok(archive) => Some(archive),
+                                Err(e) => {
+                                    let mut report = JobReport::new(node);
+                                    report.errors.push(JobError::Archive(e));
+                                    return (ind, report);
+                                }
+                            },
+                            None => None,
+                        };
+
+                        let target = SshTarget {
+                            node,
+                            cwd,
+                            deadline: ARGS.deadline.map(|deadline| Instant::now() + deadline),
+                            skew: skews[ind],
+                            ident_width,
+                            out_lines: Arc::new(AtomicU64::new(0)),
+                            err_lines: Arc::new(AtomicU64::new(0)),
+                            archive: archive.as_ref(),
+                        };
+
+                        let (started, start) = (Utc::now(), Instant::now());
+                        let mut report = match &ARGS.follow {
+                            Some(path) => follow_on_node(scope, &target, path, s, err_send),
+                            None => run_on_node(scope, &target, s, err_send),
+                        };
+                        report.skew = target.skew;
+                        report.started = Some(started);
+                        report.elapsed = Some(start.elapsed());
+                        if let Some(Err(e)) = archive.map(|archive| archive.write_rc(&report)) {
+                            report.errors.push(JobError::Archive(e));
+                        }
+                        if ARGS.stop_on_failure && !report.success() {
+                            stop.store(true, Ordering::SeqCst);
+                        }
+
+                        (ind, report)
+                    })
+                    .collect::<Vec<_>>()
+            }));
+        }
+
+        let written = if ARGS.merging() {
+            merge_outputs(recvs)
+        } else if ARGS.stream {
+            stream_outputs(recvs)
+        } else {
+            write_outputs_inorder(recvs)
+        };
+
+        // with nowhere left to write, stop following or the nodes would be followed forever
+        if written.is_err() && ARGS.follow.is_some() {
+            STOPPING.store(true, Ordering::SeqCst);
+        }
+
+        let mut reports: Vec<_> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        reports.sort_by_key(|(ind, _)| *ind);
+
+        let reports = reports.into_iter().map(|(_, report)| report).collect();
+
+        (reports, written)
+    })
+    .unwrap()
+}
+
+/// Where to run ssh for a single node, and when to give up on it
+#[derive(Debug)]
+struct SshTarget<'a> {
+    node: &'a Node,
+    cwd: &'a Path,
+    deadline: Option<Instant>,
+    skew: Option<Skew>,
+    ident_width: usize,
+    /// Lines read from the node's stdout and stderr so far, kept across --follow reconnects
+    out_lines: Arc<AtomicU64>,
+    err_lines: Arc<AtomicU64>,
+    archive: Option<&'a Archive>,
+}
+
+/// Run the command on a single node, sending its output into send and err_send
+///
+/// Never fails outright, anything that goes wrong is recorded in the returned report so the
+/// other nodes can carry on.
+fn run_on_node(
+    scope: &crossbeam::thread::Scope<'_>,
+    target: &SshTarget<'_>,
+    send: Sender<Line>,
+    err_send: Option<Sender<Line>>,
+) -> JobReport {
+    let mut report = JobReport::new(target.node);
+
+    run_ssh(
+        scope,
+        target,
+        &ARGS.command,
+        (send, err_send),
+        None,
+        &mut report,
+    );
+
+    debug!(?report);
+
+    report
+}
+
+/// Follow path on a single node until stopped, reconnecting whenever the connection drops
+///
+/// Each reconnect resumes from the end of the last complete line received, so a line cut off by
+/// the drop is printed again in full and nothing else is repeated or lost.
+///
+/// That offset counts bytes across any rotation `tail -F` followed while connected, so it only
+/// holds for the file it started in. A reconnect that finds the file has a new inode or is shorter
+/// than the offset follows the new file from its start instead. Lines the old file got after the
+/// drop are lost then, and lines already printed from the new file are printed again. A file
+/// truncated in place that has grown past the offset again by the reconnect isn't noticed, and is
+/// resumed part way through.
+fn follow_on_node(
+    scope: &crossbeam::thread::Scope<'_>,
+    target: &SshTarget<'_>,
+    path: &Path,
+    send: Sender<Line>,
+    err_send: Option<Sender<Line>>,
+) -> JobReport {
+    let mut report = JobReport::new(target.node);
+    let position = Arc::new(FollowPosition::new());
+
+    loop {
+        let script = follow_script(path, position.resume());
+        let outputs = (send.clone(), err_send.clone());
+
+        run_ssh(
+            scope,
+            target,
+            &[script],
+            outputs,
+            Some(&position),
+            &mut report,
+        );
+
+        if STOPPING.load(Ordering::SeqCst) {
+            report.status = None;
+            break;
+        }
+
+        let fatal = report.errors.iter().any(|e| {
+            matches!(
+                e,
+                JobError::Spawn(_) | JobError::ChannelClosed(_) | JobError::Timeout(_)
+            )
+        });
+        if fatal {
+            break;
+        }
+
+        warn!(message = "lost connection, reconnecting", ident = %report.ident, status = ?report.status);
+
+        // whatever went wrong with the old connection doesn't count against the new one
+        report.errors.clear();
+        thread::sleep(FOLLOW_RECONNECT_DELAY);
+    }
+
+    debug!(?report);
+
+    report
+}
+
+/// Run remote on the target node over ssh, recording how it went in report
+///
+/// With resume, stdout is the output of a follow_script and resume is kept up to date with how
+/// far it has got.
+fn run_ssh(
+    scope: &crossbeam::thread::Scope<'_>,
+    target: &SshTarget<'_>,
+    remote: &[String],
+    (send, err_send): (Sender<Line>, Option<Sender<Line>>),
+    resume: Option<&Arc<FollowPosition>>,
+    report: &mut JobReport,
+) {
+    let ident = report.ident.clone();
+    let zone = target.node.timezone.unwrap_or_else(Zone::utc);
+    let ident_width = target.ident_width;
+    let skew = target
+        .skew
+        .map_or_else(chrono::Duration::zero, |skew| skew.offset);
+
+    let mut cmd = ssh_command(target.node);
+    let _ = cmd
+        .arg("cd")
+        .arg(target.cwd)
+        .arg(";")
+        .args(remote)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(match ARGS.stderr {
+            StderrMode::Drop if target.archive.is_none() => Stdio::null(),
+            _ => Stdio::piped(),
+        });
+
+    debug!(?cmd);
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            report.errors.push(JobError::Spawn(e));
+            return;
+        }
+    };
+
+    debug!(?child);
+
+    let errors = child.stderr.take().map(|errors| {
+        let copy = target.archive.map(|archive| Arc::clone(&archive.err));
+        let mut job = ActiveJob {
+            incoming_lines: Some(TeeRead::new(errors, copy, true)),
+            ident: ident.clone(),
+            node: target.node.clone(),
+            stream: Stream::Stderr,
+            zone,
+            skew,
+            unsorted: 0,
+            ident_width,
+            lines: Arc::clone(&target.err_lines),
+        };
+
+        scope.spawn(move |_| {
+            let result = match (err_send, ARGS.stderr) {
+                (Some(send), _) => job.process_into(send),
+                (None, StderrMode::Prefix) => job.forward_to_stderr(),
+                // only being read for --output-dir
+                (None, _) => job.discard(),
+            };
+            (result, job.unsorted)
+        })
+    });
+
+    let output = child.stdout.take().unwrap();
+    let copy = target.archive.map(|archive| Arc::clone(&archive.log));
+
+    // a line cut off by a dropped --follow connection is sent again in full on reconnect
+    let output: Box<dyn JobRead> = match resume {
+        Some(position) => Box::new(TeeRead::new(
+            ResumeRead::new(output, Arc::clone(position)),
+            copy,
+            false,
+        )),
+        None => Box::new(TeeRead::new(output, copy, true)),
+    };
+
+    let mut job = ActiveJob {
+        incoming_lines: Some(output),
+        ident,
+        node: target.node.clone(),
+        stream: Stream::Stdout,
+        zone,
+        skew,
+        unsorted: 0,
+        ident_width,
+        lines: Arc::clone(&target.out_lines),
+    };
+
+    debug!(?job);
+
+    let output = scope.spawn(move |_| (job.process_into(send), job.unsorted));
+
+    match wait_with_deadline(&mut child, target.deadline) {
+        Ok((status, timed_out)) => {
+            report.status = Some(status);
+            if timed_out {
+                report
+                    .errors
+                    .push(JobError::Timeout(ARGS.deadline.unwrap()));
+            }
+        }
+        Err(e) => report.errors.push(JobError::Wait(e)),
+    }
+
+    // killing ssh closes its pipes so the readers flush whatever partial output they already
+    // have and finish
+    let readers = std::iter::once(output).chain(errors);
+    for reader in readers {
+        let (result, unsorted) = reader.join().unwrap();
+        report.unsorted += unsorted;
+        if let Err(e) = result {
+            report.errors.push(e);
+        }
+    }
+}
+
+/// ssh to node with the usual options, ready for the remote command to be added
+fn ssh_command(node: &Node) -> Command {
+    let mut cmd = Command::new("ssh");
+    let _ = cmd
+        .args("-o UserKnownHostsFile=/dev/null -o StrictHostKeyChecking=no -q".split_whitespace())
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", ARGS.timeout));
+
+    if let Some(port) = node.port {
+        let _ = cmd.arg("-p").arg(port.to_string());
+    }
+
+    let _ = cmd.arg(node.ssh_destination());
+
+    cmd
+}
+
+/// Remote shell script that follows path from its current end, or resumes following it from an
+/// offset into the file with the given inode
+///
+/// When resuming and path has been rotated since, it is followed from its start instead. The
+/// script first prints the offset it starts from and the inode of the file so a dropped
+/// connection can be resumed, and kills its tail once its stdin closes, which happens whenever
+/// ssh goes away.
+fn follow_script(path: &Path, resume: Option<(u64, u64)>) -> String {
+    let path = shell_quote(&path.to_string_lossy());
+
+    let start = match resume {
+        Some((inode, offset)) => format!(
+            "start=0; [ \"$(stat -c %i -- {path} 2>/dev/null)\" = {inode} ] && \
+             [ \"$(stat -c %s -- {path})\" -ge {offset} ] && start={offset}",
+            path = path,
+            inode = inode,
+            offset = offset
+        ),
+        None => format!("start=$(stat -c %s -- {} 2>/dev/null || echo 0)", path),
+    };
+
+    format!(
+        "{start}; echo $start $(stat -c %i -- {path} 2>/dev/null || echo 0); \
+         tail -c +$((start + 1)) -F -- {path} & read _; kill $! 2>/dev/null",
+        start = start,
+        path = path
+    )
+}
+
+/// Quote s so the remote shell sees it as a single word
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Any reader a job can read a node's output from
+trait JobRead: Read + Debug + Send {}
+
+impl<R: Read + Debug + Send> JobRead for R {}
+
+/// How far --follow has got into the file on a single node, kept across reconnects
+#[derive(Debug)]
+struct FollowPosition {
+    /// Inode of the file when the current connection started
+    inode: AtomicU64,
+    offset: AtomicU64,
+}
+
+impl FollowPosition {
+    fn new() -> Self {
+        FollowPosition {
+            inode: AtomicU64::new(0),
+            offset: AtomicU64::new(UNKNOWN_OFFSET),
+        }
+    }
+
+    /// The inode and offset to resume from, None until the remote side has said where it started
+    fn resume(&self) -> Option<(u64, u64)> {
+        match self.offset.load(Ordering::SeqCst) {
+            UNKNOWN_OFFSET => None,
+            offset => Some((self.inode.load(Ordering::SeqCst), offset)),
+        }
+    }
+}
+
+/// The output of a follow_script, keeping track of how far into the followed file it has got
+///
+/// The offset and inode line the script starts with is consumed here, after that the offset is
+/// advanced past every complete line read.
+#[derive(Debug)]
+struct ResumeRead<R> {
+    inner: BufReader<R>,
+    position: Arc<FollowPosition>,
+    started: bool,
+    /// Bytes read since the last newline
+    partial: u64,
+}
+
+impl<R: Read> ResumeRead<R> {
+    fn new(read: R, position: Arc<FollowPosition>) -> Self {
+        ResumeRead {
+            inner: BufReader::new(read),
+            position,
+            started: false,
+            partial: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ResumeRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            let mut start = String::new();
+            let _ = self.inner.read_line(&mut start)?;
+
+            // the connection dropped before the script got going
+            if !start.ends_with('\n') {
+                return Ok(0);
+            }
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, start.trim());
+            let mut fields = start.split_whitespace().map(str::parse);
+            let (offset, inode) = match (fields.next(), fields.next()) {
+                (Some(Ok(offset)), Some(Ok(inode))) => (offset, inode),
+                _ => return Err(invalid()),
+            };
+            self.position.inode.store(inode, Ordering::SeqCst);
+            self.position.offset.store(offset, Ordering::SeqCst);
+            self.started = true;
+        }
+
+        let read = self.inner.read(buf)?;
+
+        match buf[..read].iter().rposition(|&b| b == b'\n') {
+            Some(newline) => {
+                let complete = self.partial + newline as u64 + 1;
+                let _ = self.position.offset.fetch_add(complete, Ordering::SeqCst);
+                self.partial = (read - newline - 1) as u64;
+            }
+            None => self.partial += read as u64,
+        }
+
+        Ok(read)
+    }
+}
+
+/// A node's files in `--output-dir`
+#[derive(Debug)]
+struct Archive {
+    log: Arc<File>,
+    err: Arc<File>,
+    rc: PathBuf,
+}
+
+impl Archive {
+    /// Create the files for node in dir, replacing any left by an earlier run
+    fn create(dir: &Path, node: &Node) -> io::Result<Self> {
+        let name = node.backplane_ip.replace('/', "_");
+        let path = |ext| dir.join(format!("{}.{}", name, ext));
+
+        let rc = path("rc");
+        if rc.exists() {
+            std::fs::remove_file(&rc)?;
+        }
+
+        Ok(Archive {
+            log: Arc::new(File::create(path("log"))?),
+            err: Arc::new(File::create(path("err"))?),
+            rc,
+        })
+    }
+
+    /// Record how the command exited, as a shell would report it in `$?`. Nothing is written if
+    /// it never exited
+    fn write_rc(&self, report: &JobReport) -> io::Result<()> {
+        let code = match report.status {
+            Some(status) => status
+                .code()
+                .or_else(|| status.signal().map(|sig| 128 + sig)),
+            None => None,
+        };
+
+        match code {
+            Some(code) => std::fs::write(&self.rc, format!("{}\n", code)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reader that copies every complete line it reads into a file for `--output-dir`
+///
+/// Without keep_partial an unfinished last line isn't copied, since --follow sends it again in
+/// full after reconnecting. A failure to write the copy is warned about and the copying stops,
+/// the lines are still read as normal.
+#[derive(Debug)]
+struct TeeRead<R> {
+    inner: R,
+    copy: Option<Arc<File>>,
+    keep_partial: bool,
+    /// Bytes read since the last newline
+    partial: Vec<u8>,
+}
+
+impl<R: Read> TeeRead<R> {
+    fn new(read: R, copy: Option<Arc<File>>, keep_partial: bool) -> Self {
+        TeeRead {
+            inner: read,
+            copy,
+            keep_partial,
+            partial: vec![],
+        }
+    }
+
+    fn write_copy(&mut self, data: &[u8]) {
+        if let Some(copy) = &self.copy {
+            if let Err(e) = (&**copy).write_all(data) {
+                warn!(message = "failed to save output", %e);
+                self.copy = None;
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for TeeRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if self.copy.is_none() {
+            return Ok(read);
+        }
+
+        if read == 0 {
+            if self.keep_partial {
+                let partial = std::mem::take(&mut self.partial);
+                self.write_copy(&partial);
+            }
+            return Ok(0);
+        }
+
+        match buf[..read].iter().rposition(|&b| b == b'\n') {
+            Some(newline) => {
+                let mut complete = std::mem::take(&mut self.partial);
+                complete.extend_from_slice(&buf[..=newline]);
+                self.write_copy(&complete);
+                self.partial.extend_from_slice(&buf[newline + 1..read]);
+            }
+            None => self.partial.extend_from_slice(&buf[..read]),
+        }
+
+        Ok(read)
+    }
+}
+
+/// Wait for child to exit, killing it if it is still running once deadline has passed
+///
+/// When --follow is stopped the child's stdin is closed, which tells the follow_script to stop
+/// its tail and exit. Returns the exit status and whether the child was killed for running too
+/// long
+#[tracing::instrument]
+fn wait_with_deadline(
+    child: &mut Child,
+    deadline: Option<Instant>,
+) -> io::Result<(ExitStatus, bool)> {
+    if deadline.is_none() && ARGS.follow.is_none() {
+        return Ok((child.wait()?, false));
+    }
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+
+        if STOPPING.load(Ordering::SeqCst) {
+            drop(child.stdin.take());
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            debug!(message = "deadline exceeded, killing", ?child);
+            let _ = child.kill();
+            return Ok((child.wait()?, true));
+        }
+
+        thread::sleep(DEADLINE_POLL_INTERVAL);
+    }
+}
+
+/// How far a node's clock is ahead of ours, as measured by measure_skew
+#[derive(Debug, Clone, Copy)]
+struct Skew {
+    offset: chrono::Duration,
+    /// Round trip time of the sample the offset came from, which bounds how wrong it can be
+    rtt: chrono::Duration,
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |d: chrono::Duration| d.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0;
+
+        write!(
+            f,
+            "clock skew {:+.3}ms (rtt {:.3}ms)",
+            millis(self.offset),
+            millis(self.rtt)
+        )
+    }
+}
+
+/// Measure the clock skew of every node at once, logging any that can't be measured
+#[tracing::instrument(skip(nodes))]
+fn measure_skews(nodes: &[Node]) -> Vec<Option<Skew>> {
+    crossbeam::scope(|scope| {
+        let handles: Vec<_> = nodes
+            .iter()
+            .map(|node| scope.spawn(move |_| measure_skew(node)))
+            .collect();
+
+        handles
+            .into_iter()
+            .zip(nodes)
+            .map(|(handle, node)| match handle.join().unwrap() {
+                Ok(skew) => {
+                    debug!(ident = %node.backplane_ip, %skew);
+                    Some(skew)
+                }
+                Err(e) => {
+                    warn!(message = "couldn't measure clock skew, not correcting it", ident = %node.backplane_ip, %e);
+                    None
+                }
+            })
+            .collect()
+    })
+    .unwrap()
+}
+
+/// Measure how far node's clock is ahead of ours by asking for its time over ssh
+///
+/// Each sample assumes the remote clock was read halfway through the round trip, so the sample
+/// with the shortest round trip is the one trusted. A node that takes longer than `--timeout` to
+/// answer any sample is given up on.
+fn measure_skew(node: &Node) -> Result<Skew, Error> {
+    let rounds: Vec<_> = (1..=SKEW_SAMPLES).map(|i| i.to_string()).collect();
+    let script = format!(
+        "for i in {}; do read _ || exit; date +%s.%N; done",
+        rounds.join(" ")
+    );
+
+    let mut child = ssh_command(node)
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let result = sample_skew(&mut child);
+
+    // a node that stopped answering is still waiting for the rest of its samples
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let _ = child.wait()?;
+
+    result
+}
+
+/// Take SKEW_SAMPLES samples of the clock of the node running a measure_skew script
+fn sample_skew(child: &mut Child) -> Result<Skew, Error> {
+    let timeout = Duration::from_secs(ARGS.timeout.into());
+    let mut input = child.stdin.take().unwrap();
+    let output = BufReader::new(child.stdout.take().unwrap());
+
+    // read on another thread so a node that never answers can be given up on, it finishes once
+    // the child is gone
+    let (send, replies) = crossbeam::channel::unbounded();
+    let _ = thread::spawn(move || {
+        for reply in output.lines() {
+            if send.send(reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut best: Option<Skew> = None;
+    for received in 0..SKEW_SAMPLES {
+        let sent = Utc::now();
+        input.write_all(b"\n")?;
+
+        let reply = match replies.recv_timeout(timeout) {
+            Ok(reply) => reply?,
+            Err(RecvTimeoutError::Timeout) => {
+                failure::bail!("no answer within {}s", ARGS.timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                failure::bail!("connection closed after {} samples", received)
+            }
+        };
+        let received = Utc::now();
+
+        let remote = TimestampFormat::Epoch
+            .parse(reply.trim(), Zone::utc())
+            .ok_or_else(|| failure::format_err!("unexpected time from date: {:?}", reply))?;
+
+        let rtt = received - sent;
+        let skew = Skew {
+            offset: remote.with_timezone(&Utc) - (sent + rtt / 2),
+            rtt,
+        };
+
+        if best.is_none_or(|best| skew.rtt < best.rtt) {
+            best = Some(skew);
+        }
+    }
+
+    Ok(best.unwrap())
+}
+
+fn print_summary<'a>(reports: impl Iterator<Item = &'a JobReport>) {
+    for report in reports {
+        eprintln!("{}", report);
+    }
+}
+
+/// Finish --output json with a summary object for each node
+fn write_json_summary(reports: &[JobReport]) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for report in reports {
+        serde_json::to_writer(&mut out, &report.to_json())?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Turn the per node reports into the result of the whole run according to the fail_on policy
+///
+/// The error lists the report of every node that failed.
+#[tracing::instrument]
+fn check_reports(reports: &[JobReport], fail_on: FailOn) -> Result<(), Error> {
+    let failed: Vec<_> = reports.iter().filter(|report| !report.success()).collect();
+
+    let run_failed = match fail_on {
+        FailOn::Any => !failed.is_empty(),
+        FailOn::All => !reports.is_empty() && failed.len() == reports.len(),
+    };
+
+    if run_failed {
+        let causes: String = failed
+            .iter()
+            .map(|report| format!("\n    {}", report))
+            .collect();
+        failure::bail!(
+            "command failed on {} of {} nodes{}",
+            failed.len(),
+            reports.len(),
+            causes
+        );
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument]
+fn get_node_list_from_file(path: impl AsRef<Path> + Debug) -> Result<Vec<Node>, Error> {
+    let contents = std::fs::read_to_string(&path)?;
+
+    parse_inventory(&contents)
+        .map_err(|e| failure::format_err!("invalid inventory {:?}: {}", path.as_ref(), e))
+}
+
+/// Parse an inventory in either the toml format or the legacy `uuid backplane_ip main_ip` format
+#[tracing::instrument(skip(contents))]
+fn parse_inventory(contents: &str) -> Result<Vec<Node>, Error> {
+    let toml_error = match toml::from_str::<Inventory>(contents) {
+        Ok(inventory) => return Ok(inventory.nodes),
+        Err(e) => e,
+    };
+
+    debug!(message = "not a toml inventory, trying legacy format", %toml_error);
+
+    let mut nodes = vec![];
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+
+        let (uuid, backplane_ip, main_ip) = match fields[..] {
+            [] => continue,
+            [uuid, backplane_ip, main_ip] => (uuid, backplane_ip, main_ip),
+            _ => failure::bail!(
+                "line {}: expected `uuid backplane_ip main_ip` or toml ({})",
+                lineno + 1,
+                toml_error
+            ),
+        };
+
+        nodes.push(Node {
+            hostname: None,
+            uuid: Some(uuid.into()),
+            main_ip: main_ip.into(),
+            backplane_ip: backplane_ip.into(),
+            user: None,
+            port: None,
+            tags: BTreeMap::new(),
+            timezone: None,
+        });
+    }
+
+    Ok(nodes)
+}
+
+#[tracing::instrument]
+fn get_node_list() -> Result<Vec<Node>, Error> {
+    get_node_list_from_file(panic!("redacted"))
+}
+
+/// Narrow the inventory down to the nodes chosen by the selection arguments, keeping their order
+#[tracing::instrument(skip(nodes))]
+fn select_nodes(mut nodes: Vec<Node>, args: &Cli) -> Vec<Node> {
+    nodes.retain(|node| {
+        args.only.iter().all(|tag| tag.matches(node))
+            && (args.select.is_empty() || args.select.iter().any(|pat| pat.matches(node)))
+            && !args.exclude.iter().any(|pat| pat.matches(node))
+    });
+
+    if let Some(count) = args.first {
+        nodes.truncate(count);
+    }
+
+    if let Some(count) = args.random {
+        if count < nodes.len() {
+            let mut picked =
+                rand::seq::index::sample(&mut rand::thread_rng(), nodes.len(), count).into_vec();
+            picked.sort_unstable();
+            nodes = picked.into_iter().map(|ind| nodes[ind].clone()).collect();
+        }
+    }
+
+    nodes
+}
+
+#[tracing::instrument]
+fn run() -> Result<(), Error> {
+    let args = ARGS.deref();
+    debug!(?args);
+
+    args.check()?;
+
+    let cwd = std::env::current_dir()?;
+
+    let mut nodes = select_nodes(get_node_list()?, args);
+    nodes.extend_from_slice(&args.nodes);
+
+    if nodes.is_empty() {
+        failure::bail!("no nodes selected");
+    }
+
+    if args.follow.is_some() {
+        ctrlc::set_handler(|| STOPPING.store(true, Ordering::SeqCst))?;
+    }
+
+    if let Some(dir) = &args.output_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let skews = if args.correct_skew {
+        measure_skews(&nodes)
+    } else {
+        vec![None; nodes.len()]
+    };
+
+    let (reports, written) = spawn_jobs(&nodes, &skews, &cwd);
+
+    let checked = check_reports(&reports, args.fail_on);
+
+    match args.output {
+        // nodes that failed the run are reported by its error instead
+        OutputMode::Text => print_summary(
+            reports
+                .iter()
+                .filter(|report| checked.is_ok() || report.success()),
+        ),
+        // a broken stdout is reported by written below
+        OutputMode::Json if written.is_ok() => write_json_summary(&reports)?,
+        OutputMode::Json => {}
+    }
+
+    written?;
+
+    checked
+}
+
+#[tracing::instrument]
+fn main() {
+    scale::init_script("info");
+
+    if let Some(shell) = ARGS.shell {
+        print_completions(shell);
+    } else {
+        scale::handle_script_result(run());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grab_nodes() {
+        scale::init_script("info");
+
+        let nodes = get_node_list_from_file(panic!("redacted")).unwrap();
+
+        info!(?nodes);
+
+        assert_eq!(3, nodes.len());
+    }
+
+    #[test]
+    fn inventory_formats() {
+        let legacy = "uuid-1 10.1.0.1 10.0.0.1\nuuid-2 10.1.0.2 10.0.0.2\n";
+        let nodes = parse_inventory(legacy).unwrap();
+        assert_eq!(2, nodes.len());
+        assert_eq!(Some("uuid-2"), nodes[1].uuid.as_deref());
+        assert_eq!("10.0.0.2", nodes[1].main_ip);
+
+        let toml = r#"
+            [[node]]
+            hostname = "node01"
+            uuid = "uuid-1"
+            main_ip = "10.0.0.1"
+            backplane_ip = "10.1.0.1"
+            user = "admin"
+            port = 2222
+            tags = { role = "storage", rack = "3" }
+        "#;
+        let nodes = parse_inventory(toml).unwrap();
+        assert_eq!(1, nodes.len());
+        assert_eq!("admin@10.0.0.1", nodes[0].ssh_destination());
+        assert_eq!(Some(2222), nodes[0].port);
+        assert_eq!("storage", nodes[0].tags["role"]);
+
+        assert!(parse_inventory("uuid-1 10.1.0.1\n").is_err());
+    }
+
+    #[test]
+    fn node_selection() {
+        let inventory = r#"
+            [[node]]
+            hostname = "node01"
+            main_ip = "10.0.0.1"
+            backplane_ip = "10.1.0.1"
+            tags = { role = "storage" }
+
+            [[node]]
+            hostname = "node02"
+            main_ip = "10.0.0.2"
+            backplane_ip = "10.1.0.2"
+            tags = { role = "compute" }
+
+            [[node]]
+            hostname = "node10"
+            main_ip = "10.0.0.10"
+            backplane_ip = "10.1.0.10"
+            tags = { role = "storage" }
+        "#;
+        let nodes = parse_inventory(inventory).unwrap();
+
+        let selected = |args: &[&str]| {
+            let args = Cli::from_iter(std::iter::once("sca").chain(args.iter().cloned()));
+            select_nodes(nodes.clone(), &args)
+                .into_iter()
+                .map(|node| node.main_ip)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["10.0.0.1", "10.0.0.10"], selected(&["--only", "role=storage"]));
+        assert_eq!(vec!["10.0.0.1", "10.0.0.2"], selected(&["--nodes", "node[01-08]"]));
+        assert_eq!(vec!["10.0.0.2"], selected(&["--exclude", "10.1.0.1*"]));
+        assert_eq!(vec!["10.0.0.2"], selected(&["--nodes", "node0[!1]"]));
+        assert!("node[08-01]".parse::<NodePattern>().is_err());
+        assert!("node[0-99999999]".parse::<NodePattern>().is_err());
+        assert_eq!(vec!["10.0.0.1"], selected(&["--first", "1"]));
+        assert_eq!(2, selected(&["--random", "2"]).len());
+    }
+
+    #[test]
+    fn fail_on_policy() {
+        let report = |code| JobReport {
+            ident: "10.0.0.1".into(),
+            status: Some(ExitStatus::from_raw(code << 8)),
+            errors: vec![],
+            skew: None,
+            unsorted: 0,
+            started: None,
+            elapsed: None,
+        };
+
+        let partial = vec![report(0), report(1)];
+        assert!(check_reports(&partial, FailOn::Any).is_err());
+        assert!(check_reports(&partial, FailOn::All).is_ok());
+
+        let total = vec![report(2), report(1)];
+        assert_eq!(
+            "command failed on 2 of 2 nodes\n    10.0.0.1: exit 2\n    10.0.0.1: exit 1",
+            check_reports(&total, FailOn::All).unwrap_err().to_string()
+        );
+
+        let skewed = JobReport {
+            skew: Some(Skew {
+                offset: chrono::Duration::microseconds(-12_500),
+                rtt: chrono::Duration::microseconds(250),
+            }),
+            ..report(1)
+        };
+        assert_eq!(
+            "10.0.0.1: exit 1, clock skew -12.500ms (rtt 0.250ms)",
+            skewed.to_string()
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        let line = b"caf\xe9 ok".to_vec();
+
+        assert_eq!(line, Encoding::Raw.decode(line.clone()));
+        assert_eq!(
+            "caf\u{fffd} ok".as_bytes(),
+            &Encoding::Lossy.decode(line.clone())[..]
+        );
+        assert_eq!(b"caf\\xe9 ok", &Encoding::Hex.decode(line)[..]);
+    }
+
+    #[test]
+    fn follow_resume() {
+        let script = follow_script(Path::new("it's.log"), Some((7, 42)));
+        assert!(script.starts_with(
+            "start=0; [ \"$(stat -c %i -- 'it'\\''s.log' 2>/dev/null)\" = 7 ] && \
+             [ \"$(stat -c %s -- 'it'\\''s.log')\" -ge 42 ] && start=42; echo $start"
+        ));
+
+        let position = Arc::new(FollowPosition::new());
+        let mut read = ResumeRead::new(&b"100 7\nfirst\nsecond\ncut o"[..], Arc::clone(&position));
+        let mut out = String::new();
+        let _ = read.read_to_string(&mut out).unwrap();
+
+        assert_eq!("first\nsecond\ncut o", out);
+        assert_eq!(Some((7, 100 + 13)), position.resume());
+
+        let position = Arc::new(FollowPosition::new());
+        let mut read = ResumeRead::new(&b"10"[..], Arc::clone(&position));
+        assert_eq!(0, read.read(&mut [0; 8]).unwrap());
+        assert_eq!(None, position.resume());
+
+        let mut read = ResumeRead::new(&b"10\n"[..], Arc::clone(&position));
+        assert!(read.read(&mut [0; 8]).is_err());
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        let parse = |format: &str, line: &str| {
+            let format: TimestampFormat = format.parse().unwrap();
+            let regex = Regex::new(&format!("^{}", format.pattern())).unwrap();
+            let found = regex.find(line.as_bytes()).unwrap();
+            assert_eq!(b" message", &line.as_bytes()[found.end()..]);
+            format
+                .parse(&line[found.range()], Zone::utc())
+                .unwrap()
+                .with_timezone(&Utc)
+                .to_rfc3339()
+        };
+
+        let expected = "2020-01-02T03:04:05.123+00:00";
+        assert_eq!(
+            expected,
+            parse("iso8601", "2020-01-02 03:04:05.123000 message")
+        );
+        assert_eq!(
+            expected,
+            parse("iso8601", "2020-01-02T03:04:05,123Z message")
+        );
+        assert_eq!(
+            expected,
+            parse("iso8601", "2020-01-02T05:04:05.123+0200 message")
+        );
+        assert_eq!(
+            expected,
+            parse("rfc3339", "2020-01-01T22:04:05.123-05:00 message")
+        );
+        assert_eq!(expected, parse("epoch", "1577934245.123 message"));
+        assert_eq!(expected, parse("epoch-millis", "1577934245123 message"));
+        assert_eq!(
+            expected,
+            parse("%d/%b/%Y:%H:%M:%S%.f", "02/Jan/2020:03:04:05.123 message")
+        );
+        assert_eq!(
+            "2020-01-02T02:04:05+00:00",
+            parse("%F %T %z", "2020-01-02 03:04:05 +0100 message")
+        );
+
+        let syslog = parse("syslog", "Jan  2 03:04:05 message");
+        assert!(syslog.ends_with("-01-02T03:04:05+00:00"));
+        let leap_day = parse("syslog", "Feb 29 03:04:05 message");
+        assert!(leap_day.ends_with("-02-29T03:04:05+00:00"));
+
+        assert!("%Q".parse::<TimestampFormat>().is_err());
+        assert!("isoish".parse::<TimestampFormat>().is_err());
+    }
+
+    #[test]
+    fn timezones() {
+        let inventory = r#"
+            [[node]]
+            main_ip = "10.0.0.1"
+            backplane_ip = "10.1.0.1"
+            timezone = "America/New_York"
+        "#;
+        let zone = parse_inventory(inventory).unwrap()[0].timezone.unwrap();
+        assert!(parse_inventory(&inventory.replace("America/New_York", "Mars/Base")).is_err());
+
+        let iso = TimestampFormat::Iso8601;
+        let utc = Zone::utc();
+
+        // winter and summer offsets, plus a line that carries its own offset
+        let winter = iso.parse("2020-01-02 03:04:05.000000", zone).unwrap();
+        assert_eq!("2020-01-02 08:04:05.000000+00:00", utc.render(winter));
+        let summer = iso.parse("2020-07-02 03:04:05.000000", zone).unwrap();
+        assert_eq!("2020-07-02 07:04:05.000000+00:00", utc.render(summer));
+        let own = iso.parse("2020-07-02 03:04:05+02:00", zone).unwrap();
+        assert_eq!("2020-07-02 01:04:05.000000+00:00", utc.render(own));
+
+        // the hour skipped when the clocks go forward still parses
+        assert!(iso.parse("2020-03-08 02:30:00", zone).is_some());
+
+        let kolkata: Zone = "+05:30".parse().unwrap();
+        assert_eq!("2020-07-02 06:34:05.000000+05:30", kolkata.render(own));
+        assert_eq!(kolkata, "+0530".parse().unwrap());
+        assert!("+5".parse::<Zone>().is_err());
+    }
+
+    #[test]
+    fn reorder_window() {
+        let line = |secs: i64, text: &str| Line {
+            ts: Some(DateTime::from_timestamp(secs, 0).unwrap().fixed_offset()),
+            text: text.into(),
+        };
+        let texts = |lines: Vec<Line>| {
+            lines
+                .into_iter()
+                .map(|line| String::from_utf8(line.text).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ReorderWindow::Lines(2), "2".parse().unwrap());
+        assert_eq!(
+            ReorderWindow::Time(Duration::from_secs(2)),
+            "2s".parse().unwrap()
+        );
+        assert!("soon".parse::<ReorderWindow>().is_err());
+
+        let mut buffer = ReorderBuffer::new(ReorderWindow::Lines(2));
+        assert!(buffer.push(line(2, "b")).is_empty());
+        assert!(buffer.push(line(1, "a")).is_empty());
+        assert_eq!(vec!["a"], texts(buffer.push(line(3, "c"))));
+        assert_eq!(vec!["b"], texts(buffer.push(line(3, "c2"))));
+        assert_eq!(vec!["c", "c2"], texts(buffer.finish().collect()));
+
+        let mut buffer = ReorderBuffer::new(ReorderWindow::Time(Duration::from_secs(2)));
+        assert!(buffer.push(line(10, "b")).is_empty());
+        assert!(buffer.push(line(9, "a")).is_empty());
+        assert_eq!(vec!["a"], texts(buffer.push(line(11, "c"))));
+        assert_eq!(vec!["b", "c"], texts(buffer.push(line(13, "d"))));
+
+        let mut buffer = ReorderBuffer::new(ReorderWindow::Time(Duration::from_millis(50)));
+        assert!(buffer.push(line(10, "b")).is_empty());
+        assert!(buffer.idle().is_empty());
+        thread::sleep(Duration::from_millis(60));
+        assert!(buffer.push(line(10, "c")).is_empty());
+        assert_eq!(vec!["b"], texts(buffer.idle()));
+        assert_eq!(vec!["c"], texts(buffer.finish().collect()));
+
+        let mut buffer = ReorderBuffer::new(ReorderWindow::Lines(5));
+        assert!(buffer.push(line(2, "b")).is_empty());
+        assert!(buffer.push(line(1, "a")).is_empty());
+        assert_eq!(vec!["a", "b"], texts(buffer.idle()));
+    }
+
+    #[test]
+    fn ident_width() {
+        assert_eq!("10.0.0.1   ", fit_to_width("10.0.0.1", 11));
+        assert_eq!("fd00::1", fit_to_width("fd00::1:2:3", 7));
+        assert_eq!("ノード ", fit_to_width("ノード", 7));
+        assert_eq!("ノー ", fit_to_width("ノード", 5));
+    }
+
+    #[test]
+    fn output_templates() {
+        let node = Node {
+            hostname: Some("node01".into()),
+            .."10.0.0.1".parse::<Node>().unwrap()
+        };
+        let fields = Fields {
+            ts: b"2020-01-01 00:00:00.000001",
+            node: &node,
+            ident: "10.0.0.1",
+            stream: Stream::Stderr,
+            lineno: 7,
+            line: b"hello",
+        };
+
+        let format: Template = "{ts} {node:<10}|{stream:>7} {lineno:^5} {{{line}}}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            &b"2020-01-01 00:00:00.000001 10.0.0.1  | stderr   7   {hello}"[..],
+            &format.render(&fields, false)[..]
+        );
+
+        let format: Template = "{hostname} {main_ip}: {line}".parse().unwrap();
+        assert_eq!(
+            &b"node01 10.0.0.1: hello"[..],
+            &format.render(&fields, false)[..]
+        );
+        assert_eq!(
+            &b"                 hello"[..],
+            &format.render(&fields, true)[..]
+        );
+
+        assert!("{nodes}".parse::<Template>().is_err());
+        assert!("{node:<x}".parse::<Template>().is_err());
+        assert!("{node".parse::<Template>().is_err());
+        assert!("node}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn json_output() {
+        let report = JobReport {
+            status: Some(ExitStatus::from_raw(3 << 8)),
+            elapsed: Some(Duration::from_millis(1500)),
+            ..JobReport::new(&"10.0.0.1".parse().unwrap())
+        };
+        assert_eq!(
+            r#"{"type":"summary","node":"10.0.0.1","success":false,"exit_code":3,"signal":null,"errors":[],"started":null,"elapsed_secs":1.5,"skew_ms":null,"unsorted":0}"#,
+            serde_json::to_string(&report.to_json()).unwrap()
+        );
+
+        assert_eq!(
+            &b"{\"late\":true,\"type\":\"line\"}\n{\"late\":true,\"n\":2}"[..],
+            &mark_late_json(b"{\"type\":\"line\"}\n{\"n\":2}")[..]
+        );
+    }
+
+    #[test]
+    fn output_dir() {
+        let dir = std::env::temp_dir().join(format!("sca-output-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let node: Node = "10.0.0.1".parse().unwrap();
+        let archive = Archive::create(&dir, &node).unwrap();
+
+        let mut tee = TeeRead::new(&b"one\ntw"[..], Some(Arc::clone(&archive.log)), false);
+        io::copy(&mut tee, &mut io::sink()).unwrap();
+        let mut tee = TeeRead::new(&b"two\nthree"[..], Some(Arc::clone(&archive.log)), true);
+        io::copy(&mut tee, &mut io::sink()).unwrap();
+        assert_eq!(
+            "one\ntwo\nthree",
+            std::fs::read_to_string(dir.join("10.0.0.1.log")).unwrap()
+        );
+
+        let report = JobReport {
+            status: Some(ExitStatus::from_raw(9)),
+            ..JobReport::new(&node)
+        };
+        archive.write_rc(&report).unwrap();
+        assert_eq!(
+            "137\n",
+            std::fs::read_to_string(dir.join("10.0.0.1.rc")).unwrap()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_only_options() {
+        let parses = |args: &[&str]| {
+            Cli::from_iter(std::iter::once("sca").chain(args.iter().cloned()))
+                .check()
+                .is_ok()
+        };
+
+        assert!(!parses(&["--lateness", "1s", "true"]));
+        assert!(parses(&["--merge", "--lateness", "1s", "true"]));
+        assert!(parses(&["--follow", "app.log", "--lateness", "1s"]));
+        assert!(parses(&["--merge", "--follow", "app.log"]));
+        assert!(!parses(&["--correct-skew", "true"]));
+        assert!(!parses(&["--reorder-window", "2s", "true"]));
+        assert!(!parses(&["--report-unsorted", "true"]));
+        assert!(!parses(&["--output-timezone", "UTC", "true"]));
+        assert!(!parses(&["--timestamp-regex", r"\[(.+)\]", "true"]));
+        assert!(!parses(&["--ident-width", "12", "true"]));
+        assert!(parses(&["--format", "{node} {line}", "true"]));
+        assert!(!parses(&["--merge", "--flag-late", "true"]));
+        assert!(parses(&[
+            "--merge",
+            "--lateness",
+            "1s",
+            "--flag-late",
+            "true"
+        ]));
+        assert!(parses(&["--follow", "app.log", "--flag-late"]));
+        assert!(!parses(&["--output", "json", "--format", "{line}", "true"]));
+    }
+
+    /// Reader handing out each chunk after its delay
+    #[derive(Debug)]
+    struct SlowRead(Vec<(u64, &'static [u8])>);
+
+    impl Read for SlowRead {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+
+            let (delay, chunk) = self.0.remove(0);
+            thread::sleep(Duration::from_millis(delay));
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn idle_only_after_timeout() {
+        let read = SlowRead(vec![(0, b"header\n"), (50, b"trace\n"), (300, b"next\n")]);
+        let mut seen = vec![];
+
+        let idle = Duration::from_millis(150);
+        for_each_line_or_idle(read, Stream::Stdout, Encoding::Raw, idle, |line| {
+            let line = line.map(|line| String::from_utf8(line).unwrap());
+            // how many idle calls in a row there are depends on timing, so count them as one
+            if line.is_some() || seen.last() != Some(&None) {
+                seen.push(line);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let line = |s: &str| Some(s.to_string());
+        assert_eq!(
+            vec![line("header"), line("trace"), None, line("next")],
+            seen
+        );
     }
 }